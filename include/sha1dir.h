@@ -0,0 +1,41 @@
+/* C interface to the sha1dir library, built by:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ */
+
+#ifndef SHA1DIR_H
+#define SHA1DIR_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+struct sha1dir_options {
+    /* Number of entries to hash in parallel, or 0 for one per CPU. */
+    size_t threads;
+    /* Leave out entries that are not a regular file, directory, symlink or
+     * socket, rather than failing on them. */
+    bool ignore_unknown_filetypes;
+};
+
+/* Computes the checksum of the directory at `path` into `out`, the same as
+ * printed by the sha1dir command. `options` may be NULL for the defaults.
+ * Returns 0 on success, or -1 if any entry could not be hashed, in which case
+ * sha1dir_last_error describes why. */
+int sha1dir_hash(const char *path, const struct sha1dir_options *options,
+                 uint8_t out[20]);
+
+/* Description of the most recent failure of sha1dir_hash on the calling
+ * thread, or NULL if there was none. Valid until the next failure on the same
+ * thread. */
+const char *sha1dir_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
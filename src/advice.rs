@@ -0,0 +1,73 @@
+//! Hints to the kernel about how file content is about to be accessed.
+//!
+//! All of these are purely advisory; a failure leaves the access pattern as
+//! it would be without the hint, so errors are ignored.
+
+use crate::dirfd::At;
+#[cfg(feature = "mmap")]
+use memmap::Mmap;
+#[cfg(feature = "mmap")]
+use std::cmp;
+use std::fs::File;
+
+/// Amount of each file to read ahead of the hash, enough to keep the disk
+/// busy without reading every file in a large directory into memory at once.
+const PREFETCH_LEN: i64 = 2 << 20;
+
+#[derive(Copy, Clone)]
+enum Advice {
+    Sequential,
+    WillNeed,
+    DontNeed,
+}
+
+/// Content is hashed front to back exactly once, so aggressive readahead pays
+/// off and pages behind the hash can be reclaimed early. Only the start is
+/// requested up front because the kernel would read all of a huge file at
+/// once.
+#[cfg(feature = "mmap")]
+pub fn sequential(file: &File, mmap: &Mmap) {
+    fadvise(file, 0, Advice::Sequential);
+    let addr = mmap.as_ptr() as *mut libc::c_void;
+    let willneed = cmp::min(mmap.len(), PREFETCH_LEN as usize);
+    unsafe {
+        libc::madvise(addr, mmap.len(), libc::MADV_SEQUENTIAL);
+        libc::madvise(addr, willneed, libc::MADV_WILLNEED);
+    }
+}
+
+/// Built without mmap, the content is read through the page cache instead.
+#[cfg(not(feature = "mmap"))]
+pub fn sequential(file: &File) {
+    fadvise(file, 0, Advice::Sequential);
+}
+
+/// Starts reading the beginning of a file that a worker will hash soon.
+pub fn prefetch(at: At) {
+    if let Ok(file) = at.open(0) {
+        fadvise(&file, PREFETCH_LEN, Advice::WillNeed);
+    }
+}
+
+/// Pages still mapped are not dropped, so the mapping must be gone first.
+pub fn drop_cache(file: &File) {
+    fadvise(file, 0, Advice::DontNeed);
+}
+
+// A length of 0 means to the end of the file.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn fadvise(file: &File, len: i64, advice: Advice) {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, len as libc::off_t, advice);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn fadvise(_file: &File, _len: i64, _advice: Advice) {}
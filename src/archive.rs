@@ -0,0 +1,225 @@
+//! Checksum of a tar or cpio archive, equal to the checksum of the directory
+//! that it extracts into.
+//!
+//! Every entry is hashed exactly as the traversal of the extracted tree would
+//! hash it, provided that the archive is extracted with permissions preserved
+//! (`tar -xpf`, the default for root) and contains an entry for every
+//! directory. A leading `./` or `/` on entry paths is ignored as by GNU tar,
+//! and later entries for the same path replace earlier ones.
+
+use crate::dirfd::{At, Dir};
+use crate::{
+    advice, begin, chunk_digests, chunked, cpio, die, map_file, open, Context, Mmap, Result,
+};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use tar::EntryType;
+
+pub const S_IFMT: u32 = 0o170_000;
+pub const S_IFSOCK: u32 = 0o140_000;
+pub const S_IFLNK: u32 = 0o120_000;
+pub const S_IFREG: u32 = 0o100_000;
+pub const S_IFDIR: u32 = 0o040_000;
+
+#[derive(Copy, Clone)]
+pub enum Format {
+    Tar,
+    Cpio,
+}
+
+pub enum Item {
+    File { mode: u32, content: Content },
+    Dir { mode: u32 },
+    Symlink { target: PathBuf },
+    Hardlink { target: PathBuf },
+    Socket { mode: u32 },
+}
+
+pub enum Content {
+    Range(Range<usize>),
+    Owned(Vec<u8>),
+}
+
+enum Archive {
+    Read(Vec<u8>),
+    Mapped(Option<Mmap>),
+}
+
+/// Detects the format from the content if not given.
+pub fn checksum_archive(cx: &Context, format: Option<Format>) {
+    let opened = SystemTime::now();
+    let at = At {
+        dir: &Dir::Cwd,
+        name: cx.root,
+    };
+    let mapped = open(at, cx.noatime).and_then(|mut file| {
+        let content = if file.metadata()?.len() < cx.mmap_threshold {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            Archive::Read(content)
+        } else {
+            Archive::Mapped(map_file(&file)?)
+        };
+        Ok((content, file))
+    });
+    let (content, archive_file) = match mapped {
+        Ok(mapped) => mapped,
+        Err(error) => {
+            cx.audit(opened, Path::new(""), Err(&error));
+            die(cx.label, error);
+        }
+    };
+    let archive = match &content {
+        Archive::Read(content) => content,
+        Archive::Mapped(mmap) => mmap.as_deref().unwrap_or_default(),
+    };
+    cx.audit(opened, Path::new(""), Ok(archive.len() as u64));
+
+    let format = format.unwrap_or_else(|| {
+        if cpio::is_cpio(archive) {
+            Format::Cpio
+        } else {
+            Format::Tar
+        }
+    });
+    let items = match format {
+        Format::Tar => read_tar(cx, archive),
+        Format::Cpio => cpio::read_items(cx, archive),
+    };
+    let items = match items {
+        Ok(items) => items,
+        Err(error) => die(cx.label, error),
+    };
+
+    items.par_iter().for_each(|(path, item)| {
+        if !cx.in_shard(path) {
+            return;
+        }
+        let sha = match item {
+            Item::File { mode, content } => file(cx, path, *mode, content, archive),
+            Item::Dir { mode } => {
+                cx.put_dir(path, begin(path, *mode, b'd'));
+                return;
+            }
+            Item::Symlink { target } => {
+                let mut sha = begin(path, S_IFLNK | 0o777, b'l');
+                sha.update(target.as_os_str().as_bytes());
+                sha
+            }
+            Item::Hardlink { target } => match items.get(target) {
+                Some(Item::File { mode, content }) => file(cx, path, *mode, content, archive),
+                _ => die(
+                    cx.label.join(path),
+                    format_args!("hard link to non-file {}", target.display()),
+                ),
+            },
+            Item::Socket { mode } => begin(path, *mode, b's'),
+        };
+        cx.put(path, sha);
+    });
+
+    drop(content);
+    if cx.drop_cache {
+        advice::drop_cache(&archive_file);
+    }
+}
+
+fn read_tar(cx: &Context, archive: &[u8]) -> Result<HashMap<PathBuf, Item>> {
+    let mut items = HashMap::new();
+    for entry in tar::Archive::new(archive).entries()? {
+        let mut entry = entry?;
+        let Some(path) = normalize(&entry.path()?)? else {
+            continue;
+        };
+        let mode = entry.header().mode()? & 0o7777;
+        let item = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => {
+                let start = entry.raw_file_position() as usize;
+                let end = start + entry.size() as usize;
+                if end > archive.len() {
+                    return Err("unexpected end of archive".into());
+                }
+                let content = Content::Range(start..end);
+                let mode = S_IFREG | mode;
+                Item::File { mode, content }
+            }
+            EntryType::GNUSparse => {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                let content = Content::Owned(content);
+                let mode = S_IFREG | mode;
+                Item::File { mode, content }
+            }
+            EntryType::Directory => Item::Dir {
+                mode: S_IFDIR | mode,
+            },
+            EntryType::Symlink => Item::Symlink {
+                target: link_name(&entry)?,
+            },
+            EntryType::Link => match normalize(&link_name(&entry)?)? {
+                Some(target) => Item::Hardlink { target },
+                None => return Err("hard link to archive root".into()),
+            },
+            EntryType::XGlobalHeader => continue,
+            _ if cx.ignore_unknown_filetypes => continue,
+            _ => die(cx.label.join(path), "Unsupported file type"),
+        };
+        items.insert(path, item);
+    }
+    Ok(items)
+}
+
+fn link_name(entry: &tar::Entry<&[u8]>) -> Result<PathBuf> {
+    match entry.link_name()? {
+        Some(link_name) => Ok(link_name.into_owned()),
+        None => Err("missing link name".into()),
+    }
+}
+
+pub fn normalize(path: &Path) -> Result<Option<PathBuf>> {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(component) => normal.push(component),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(format!("refusing path outside archive: {}", path.display()).into());
+            }
+        }
+    }
+    if normal.as_os_str().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(normal))
+    }
+}
+
+fn file(cx: &Context, path: &Path, mode: u32, content: &Content, archive: &[u8]) -> Sha1 {
+    let content = match content {
+        Content::Range(range) => &archive[range.clone()],
+        Content::Owned(content) => content,
+    };
+    cx.add_size(path, content.len() as u64);
+    if let Some(chunk_size) = cx.chunked.filter(|&size| content.len() as u64 > size) {
+        let digests = chunk_digests(cx, content, chunk_size);
+        return chunked(begin(path, mode, b'c'), &digests, chunk_size);
+    }
+    let mut sha = begin(path, mode, b'f');
+    cx.update(&mut sha, content);
+    if let Some(etags) = &cx.etags {
+        etags.add(path, content);
+    }
+    if let Some(spdx) = &cx.spdx {
+        spdx.add(path, content);
+    }
+    if let Some(lists) = &cx.chunk_lists {
+        lists.add(path, content);
+    }
+    sha
+}
@@ -0,0 +1,78 @@
+//! In-toto statement listing the checksum of each directory and the digest of
+//! each entry as subjects.
+//!
+//! The digests are sha1dir's own, not digests of file content, so they appear
+//! under the algorithm name `sha1dir`. The predicate is left empty for the
+//! provenance tooling to fill in or wrap.
+
+use crate::manifest::Manifest;
+use crate::{Hex, Result};
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const STATEMENT_TYPE: &str = "https://in-toto.io/Statement/v1";
+const PREDICATE_TYPE: &str = "https://github.com/dtolnay/sha1dir";
+
+pub fn write(path: &Path, manifests: &[Manifest]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"_type\": {},", Json(STATEMENT_TYPE))?;
+    writeln!(out, "  \"subject\": [")?;
+    let mut first = true;
+    for manifest in manifests {
+        let subjects = manifest
+            .entries
+            .iter()
+            .map(|entry| (manifest.label.join(&entry.path), &entry.digest));
+        for (name, digest) in [(manifest.label.clone(), &manifest.checksum)]
+            .into_iter()
+            .chain(subjects)
+        {
+            let Some(name) = name.to_str() else {
+                let name = name.to_string_lossy();
+                return Err(format!("cannot attest to non-UTF-8 path: {:?}", name).into());
+            };
+            if !first {
+                writeln!(out, ",")?;
+            }
+            first = false;
+            write!(
+                out,
+                "    {{\"name\": {}, \"digest\": {{\"sha1dir\": \"{}\"}}}}",
+                Json(name),
+                Hex(digest),
+            )?;
+        }
+    }
+    if !first {
+        writeln!(out)?;
+    }
+    writeln!(out, "  ],")?;
+    writeln!(out, "  \"predicateType\": {},", Json(PREDICATE_TYPE))?;
+    writeln!(out, "  \"predicate\": {{}}")?;
+    writeln!(out, "}}")?;
+    out.flush()?;
+    Ok(())
+}
+
+pub struct Json<'a>(pub &'a str);
+
+impl Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("\"")?;
+        for ch in self.0.chars() {
+            match ch {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                ch if ch < ' ' => write!(f, "\\u{:04x}", ch as u32)?,
+                ch => write!(f, "{}", ch)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
@@ -0,0 +1,118 @@
+//! Append-only log of every file opened for hashing.
+//!
+//! Each record is a line of tab-separated fields `<chain> <time> <bytes>
+//! <result> <path>`, where time is seconds since the Unix epoch with
+//! nanoseconds, result is `ok` or the error which prevented the file from
+//! being read, and path is absolute. Backslash, tab and newline within a field
+//! are escaped as `\\`, `\t` and `\n`.
+//!
+//! The chain field is the SHA-1 of the previous record's chain field followed
+//! by a tab and the rest of this record, so that removing or altering any
+//! record invalidates every one after it. The first record in a new log
+//! chains from 40 zeros, and a run appending to an existing log continues
+//! from its last record.
+
+use crate::{parse_hex, Hex, Result};
+use parking_lot::Mutex;
+use sha1::{Digest, Sha1};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct AuditLog {
+    path: PathBuf,
+    writer: Mutex<Writer>,
+}
+
+struct Writer {
+    file: File,
+    chain: [u8; 20],
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let mut chain = [0; 20];
+        let complete = match contents.iter().rposition(|&b| b == b'\n') {
+            Some(end) => &contents[..end],
+            None => &[],
+        };
+        if let Some(last) = complete.rsplit(|&b| b == b'\n').next() {
+            if let Some(previous) = last.get(..40).and_then(parse_hex) {
+                chain = previous;
+            }
+        }
+
+        // A record cut short by a crash is left in place as evidence; new
+        // records start on the next line.
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            file.write_all(b"\n")?;
+        }
+
+        Ok(AuditLog {
+            path: path.to_owned(),
+            writer: Mutex::new(Writer { file, chain }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes read is the length of the content on success.
+    pub fn record(
+        &self,
+        opened: SystemTime,
+        path: &Path,
+        result: std::result::Result<u64, &io::Error>,
+    ) -> Result<()> {
+        let time = opened.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = format!("{}.{:09}\t", time.as_secs(), time.subsec_nanos()).into_bytes();
+        match result {
+            Ok(bytes) => {
+                record.extend_from_slice(bytes.to_string().as_bytes());
+                record.extend_from_slice(b"\tok\t");
+            }
+            Err(error) => {
+                record.extend_from_slice(b"0\t");
+                escape(&mut record, error.to_string().as_bytes());
+                record.push(b'\t');
+            }
+        }
+        escape(&mut record, path.as_os_str().as_bytes());
+        record.push(b'\n');
+
+        // The record is written in one write, in chain order, so that
+        // concurrent workers never interleave.
+        let mut writer = self.writer.lock();
+        let mut sha = Sha1::new();
+        sha.update(Hex(&writer.chain).to_string());
+        sha.update(b"\t");
+        sha.update(&record);
+        writer.chain = sha.finalize().into();
+        let mut line = format!("{}\t", Hex(&writer.chain)).into_bytes();
+        line.extend_from_slice(&record);
+        writer.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+fn escape(out: &mut Vec<u8>, field: &[u8]) {
+    for &b in field {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b => out.push(b),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Measurements to choose the number of jobs by, for `sha1dir bench`.
+//!
+//! Hashing alone is measured on a buffer in memory, so it is bound only by
+//! the CPUs. Listing DIR is measured as done by --estimate, and hashing it
+//! with each number of jobs as with -j. The content of every file is evicted
+//! from the page cache once hashed, as with --drop-cache, so that each run
+//! reads from the device rather than from what the previous one cached.
+
+use crate::estimate::Totals;
+use crate::{canonicalize, walk, Bench, Context, DryRun, Jobs, Limits, Opt};
+use sha1::{Digest, Sha1};
+use std::hint;
+use std::io::{self, Write};
+use std::process;
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Hashed by each thread.
+const HASH_BYTES: u64 = 256 << 20;
+const BUFFER_LEN: usize = 1 << 20;
+
+pub fn run(opt: &Opt, args: &Bench) -> ! {
+    let root = canonicalize(&args.dir);
+    let label = args.dir.as_path();
+
+    row("", "JOBS", "BYTES", "ENTRIES");
+
+    for &jobs in &args.jobs {
+        let elapsed = hash_in_memory(jobs);
+        let bytes = jobs as u64 * HASH_BYTES;
+        row("sha1", &jobs.to_string(), &bytes_rate(bytes, elapsed), "");
+    }
+
+    let limits = Limits::new(opt);
+    let mut cx = Context::new(opt, label, &root, None, &limits);
+    cx.dry_run = Some(DryRun::Estimate(Totals::default()));
+    let start = Instant::now();
+    walk::checksum_dirs(slice::from_ref(&cx));
+    let elapsed = start.elapsed();
+    let Some(DryRun::Estimate(totals)) = cx.dry_run else {
+        unreachable!();
+    };
+    row("list", "", "", &entries_rate(totals.entries(), elapsed));
+
+    for &jobs in &args.jobs {
+        let mut cx = Context::new(opt, label, &root, None, &limits);
+        cx.drop_cache = true;
+        let start = Instant::now();
+        walk::checksum_dirs_with_jobs(slice::from_ref(&cx), Jobs::Threads(jobs));
+        let elapsed = start.elapsed();
+        row(
+            "hash DIR",
+            &jobs.to_string(),
+            &bytes_rate(totals.bytes(), elapsed),
+            &entries_rate(totals.entries(), elapsed),
+        );
+    }
+
+    process::exit(0);
+}
+
+fn hash_in_memory(threads: usize) -> Duration {
+    let buffer = vec![0x5a; BUFFER_LEN];
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                let mut sha = Sha1::new();
+                for _ in 0..HASH_BYTES / BUFFER_LEN as u64 {
+                    sha.update(&buffer);
+                }
+                hint::black_box(sha.finalize());
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn row(test: &str, jobs: &str, bytes: &str, entries: &str) {
+    let line = format!("{:<10}{:>6}{:>16}{:>14}", test, jobs, bytes, entries);
+    let _ = writeln!(io::stdout(), "{}", line.trim_end());
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn bytes_rate(bytes: u64, elapsed: Duration) -> String {
+    let rate = bytes as f64 / elapsed.as_secs_f64();
+    format!("{:.1} MiB/s", rate / f64::from(1 << 20))
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn entries_rate(entries: u64, elapsed: Duration) -> String {
+    let rate = entries as f64 / elapsed.as_secs_f64();
+    format!("{:.0}/s", rate)
+}
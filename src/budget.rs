@@ -0,0 +1,45 @@
+//! Limit on the total size of files mapped at the same time, shared by all
+//! worker threads.
+//!
+//! A worker waits before mapping a file until the reservations of the files
+//! currently mapped by other workers leave room for it. A file larger than
+//! the whole limit is mapped once nothing else is, rather than never.
+
+use parking_lot::{Condvar, Mutex};
+
+pub struct MapBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    released: Condvar,
+}
+
+pub struct Reservation<'a> {
+    budget: &'a MapBudget,
+    len: u64,
+}
+
+impl MapBudget {
+    pub fn new(limit: u64) -> Self {
+        MapBudget {
+            limit,
+            used: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn reserve(&self, len: u64) -> Reservation<'_> {
+        let mut used = self.used.lock();
+        while *used > 0 && *used + len > self.limit {
+            self.released.wait(&mut used);
+        }
+        *used += len;
+        Reservation { budget: self, len }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.used.lock() -= self.len;
+        self.budget.released.notify_all();
+    }
+}
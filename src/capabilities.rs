@@ -0,0 +1,86 @@
+//! Description of what this build of sha1dir supports, for --capabilities.
+//!
+//! The document is a JSON object, so that tooling that runs sha1dir can tell
+//! which options a particular version and platform accepts without parsing
+//! --help. Keys are only ever added. Every list is of the values that the
+//! option of the same name accepts, and every entry of `platform` is false
+//! where the option it names is refused on this platform or not built in.
+//! `acls` and `io_uring` are always false: access control lists are hashed
+//! only as the extended attributes that Linux stores them in, and files are
+//! read by mapping them or with plain reads.
+
+use crate::attestation::Json;
+use crate::{die, stat, xattr, Combine, Compat, Format, ManifestFormat, Opt};
+use clap::{CommandFactory, ValueEnum};
+use std::io::{self, Write};
+use std::process;
+
+pub fn run() -> ! {
+    let format_versions: Vec<String> = (sha1dir::FORMAT_V1..=sha1dir::FORMAT_VERSION)
+        .map(|version| version.to_string())
+        .collect();
+    let subcommands: Vec<String> = Opt::command()
+        .get_subcommands()
+        .map(|subcommand| Json(subcommand.get_name()).to_string())
+        .collect();
+    let platform = [
+        ("xattrs", xattr::SUPPORTED),
+        ("file_flags", stat::FLAGS_SUPPORTED),
+        ("acls", false),
+        ("io_uring", false),
+        ("mmap", cfg!(feature = "mmap")),
+        ("ionice", cfg!(target_os = "linux")),
+        ("numa_node", cfg!(target_os = "linux")),
+    ];
+
+    let mut out = Vec::new();
+    if let Err(error) = write(&mut out, &format_versions, &subcommands, &platform)
+        .and_then(|()| io::stdout().lock().write_all(&out))
+    {
+        die("--capabilities", error);
+    }
+    process::exit(0);
+}
+
+fn write(
+    out: &mut Vec<u8>,
+    format_versions: &[String],
+    subcommands: &[String],
+    platform: &[(&str, bool)],
+) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"version\": {},", Json(env!("CARGO_PKG_VERSION")))?;
+    writeln!(out, "  \"format_version\": {},", sha1dir::FORMAT_VERSION)?;
+    writeln!(
+        out,
+        "  \"format_versions\": [{}],",
+        format_versions.join(", ")
+    )?;
+    writeln!(out, "  \"format\": [{}],", values::<Format>())?;
+    writeln!(out, "  \"combine\": [{}],", values::<Combine>())?;
+    writeln!(
+        out,
+        "  \"manifest_format\": [{}],",
+        values::<ManifestFormat>()
+    )?;
+    writeln!(out, "  \"compat\": [{}],", values::<Compat>())?;
+    writeln!(out, "  \"subcommands\": [{}],", subcommands.join(", "))?;
+    writeln!(out, "  \"platform\": {{")?;
+    for (i, (name, supported)) in platform.iter().enumerate() {
+        let comma = if i + 1 < platform.len() { "," } else { "" };
+        writeln!(out, "    {}: {}{}", Json(name), supported, comma)?;
+    }
+    writeln!(out, "  }}")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+// As spelled on the command line.
+fn values<T: ValueEnum>() -> String {
+    let names: Vec<String> = T::value_variants()
+        .iter()
+        .filter_map(T::to_possible_value)
+        .map(|value| Json(value.get_name()).to_string())
+        .collect();
+    names.join(", ")
+}
@@ -0,0 +1,184 @@
+//! Export of a directory into a content-addressed store, for `sha1dir
+//! export-cas`.
+//!
+//! The content of every regular file, and the target of every symbolic link,
+//! is stored once as a blob named by its SHA-1 at `OUT/ab/cdef...`, where `ab`
+//! is the first two hex digits. A file is hard linked into the store when OUT
+//! is on the same filesystem as DIR and copied otherwise, or always with
+//! `--copy`. A blob that is already in the store is left alone, so that
+//! exports of many snapshots share whatever content they have in common.
+//!
+//! The tree of each export is written to `OUT/trees/<checksum>`. Its first
+//! line is the normal output, `<checksum>  <DIR>`, and after it there is one
+//! `<digest>  <mode>  <blob>  <path>` line per entry in bytewise order of
+//! path, with the mode in octal including the file type, and `-` in place of
+//! the blob of an entry that has none. The digests are those of --manifest,
+//! and paths are escaped and lines ended as they are there.
+//!
+//! DIR should not change during the export, since a file is linked or copied
+//! after its content is hashed.
+
+use crate::manifest::{self, Entry};
+use crate::{canonicalize, die, walk, Context, ExportCas, Hex, Limits, Opt, Result};
+use clap::error::ErrorKind;
+use clap::CommandFactory;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Cas {
+    out: PathBuf,
+    copy: bool,
+    objects: Mutex<HashMap<PathBuf, Object>>,
+    // For the names of partly written blobs.
+    next_temp: AtomicU64,
+}
+
+struct Object {
+    mode: u32,
+    blob: Option<[u8; 20]>,
+}
+
+pub fn run(opt: &Opt, args: &ExportCas) -> ! {
+    if opt.chunked.is_some() {
+        let message = "export-cas cannot be combined with --chunked";
+        Opt::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+
+    let canonical = canonicalize(&args.dir);
+    if let Err(error) = fs::create_dir_all(args.out.join("trees")) {
+        die(&args.out, error);
+    }
+    let out = canonicalize(&args.out);
+
+    let limits = Limits::new(opt);
+    let mut cx = Context::new(opt, &args.dir, &canonical, None, &limits);
+    cx.entries = Some(Mutex::new(Vec::new()));
+    cx.cas = Some(Cas {
+        out,
+        copy: args.copy,
+        objects: Mutex::new(HashMap::new()),
+        next_temp: AtomicU64::new(0),
+    });
+    walk::checksum_dirs(slice::from_ref(&cx));
+
+    let checksum = cx.checksum.bytes();
+    let mut entries = cx.entries.unwrap().into_inner();
+    manifest::sort(&mut entries);
+    let cas = cx.cas.unwrap();
+    let tree = cas.out.join("trees").join(Hex(&checksum).to_string());
+    if let Err(error) = cas.write_tree(&tree, &checksum, &args.dir, &entries, opt.eol()) {
+        die(tree, error);
+    }
+
+    let mut stdout = io::stdout().lock();
+    let line = format!("{}  {}\n", Hex(&checksum), args.dir.display());
+    if let Err(error) = stdout.write_all(line.as_bytes()) {
+        die(&args.dir, error);
+    }
+    process::exit(0);
+}
+
+impl Cas {
+    pub fn add_entry(&self, path: &Path, mode: u32) {
+        let object = Object { mode, blob: None };
+        self.objects.lock().insert(path.to_owned(), object);
+    }
+
+    /// Stores the content of the file at `source`, which hashed to `blob`.
+    pub fn add_file(&self, path: &Path, source: &Path, blob: [u8; 20]) -> io::Result<()> {
+        let dest = self.blob_path(&blob)?;
+        if !exists(&dest)? {
+            let linked = !self.copy
+                && match fs::hard_link(source, &dest) {
+                    Ok(()) => true,
+                    Err(error) => error.kind() == io::ErrorKind::AlreadyExists,
+                };
+            if !linked {
+                let temp = self.temp_path(&dest);
+                fs::copy(source, &temp)?;
+                fs::rename(&temp, &dest)?;
+            }
+        }
+        self.set_blob(path, blob);
+        Ok(())
+    }
+
+    pub fn add_content(&self, path: &Path, content: &[u8], blob: [u8; 20]) -> io::Result<()> {
+        let dest = self.blob_path(&blob)?;
+        if !exists(&dest)? {
+            let temp = self.temp_path(&dest);
+            fs::write(&temp, content)?;
+            fs::rename(&temp, &dest)?;
+        }
+        self.set_blob(path, blob);
+        Ok(())
+    }
+
+    fn set_blob(&self, path: &Path, blob: [u8; 20]) {
+        if let Some(object) = self.objects.lock().get_mut(path) {
+            object.blob = Some(blob);
+        }
+    }
+
+    fn blob_path(&self, blob: &[u8; 20]) -> io::Result<PathBuf> {
+        let hex = Hex(blob).to_string();
+        let dir = self.out.join(&hex[..2]);
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join(&hex[2..]))
+    }
+
+    // Renamed into place once complete, so that a blob in the store is never
+    // partly written.
+    fn temp_path(&self, dest: &Path) -> PathBuf {
+        let n = self.next_temp.fetch_add(1, Ordering::Relaxed);
+        let name = format!(".tmp-{}-{}", process::id(), n);
+        dest.with_file_name(name)
+    }
+
+    fn write_tree(
+        &self,
+        path: &Path,
+        checksum: &[u8; 20],
+        label: &Path,
+        entries: &[Entry],
+        eol: u8,
+    ) -> Result<()> {
+        let objects = self.objects.lock();
+        let mut out = BufWriter::new(File::create(path)?);
+        let (prefix, label) = manifest::escape(label, eol);
+        out.write_all(prefix)?;
+        write!(out, "{}  ", Hex(checksum))?;
+        out.write_all(&label)?;
+        out.write_all(&[eol])?;
+        for entry in entries {
+            let object = &objects[&entry.path];
+            let (prefix, path) = manifest::escape(&entry.path, eol);
+            out.write_all(prefix)?;
+            write!(out, "{}  {:06o}  ", Hex(&entry.digest), object.mode)?;
+            match &object.blob {
+                Some(blob) => write!(out, "{}  ", Hex(blob))?,
+                None => out.write_all(b"-  ")?,
+            }
+            out.write_all(&path)?;
+            out.write_all(&[eol])?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+fn exists(path: &Path) -> io::Result<bool> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(error),
+    }
+}
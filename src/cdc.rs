@@ -0,0 +1,187 @@
+//! Content-defined chunks of each regular file, for --chunked-manifest.
+//!
+//! Chunk boundaries are found as in `FastCDC`: a gear hash is rolled over the
+//! content starting `MIN_SIZE` bytes into each chunk, and the chunk ends after
+//! the first byte at which the hash has zeros in all the bits of a mask. The
+//! mask has more bits before `AVG_SIZE` than after it, which keeps most
+//! chunks near that size, and no chunk is longer than `MAX_SIZE`. Since a
+//! boundary depends only on the 64 bytes before it, an edit within a file
+//! moves the boundaries of the chunks around it and no others, and the chunks
+//! of two versions of a file can be compared to tell a few changed blocks from
+//! a different file altogether.
+//!
+//! The written file has a line for each chunk, in order of path and then
+//! offset: the SHA-1 of the chunk, its offset and its length in bytes, and the
+//! path of the file, escaped as in a manifest.
+
+use crate::manifest;
+use crate::{Hex, Result};
+use parking_lot::Mutex;
+use sha1::{Digest, Sha1};
+use std::cmp;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const MIN_SIZE: u64 = 16 * 1024;
+const AVG_SIZE: u64 = 64 * 1024;
+const MAX_SIZE: u64 = 256 * 1024;
+
+// Two bits more and two bits fewer than the 16 of the average size, in the
+// high bits of the hash which depend on the most bytes.
+const MASK_SMALL: u64 = !(!0 >> 18);
+const MASK_LARGE: u64 = !(!0 >> 14);
+
+// Derived with splitmix64 so that it needs no table in the source.
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut gear = [0; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        gear[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    gear
+}
+
+struct Chunk {
+    offset: u64,
+    len: u64,
+    digest: [u8; 20],
+}
+
+pub struct ChunkLists {
+    files: Mutex<Vec<(PathBuf, Vec<Chunk>)>>,
+}
+
+impl ChunkLists {
+    pub fn new() -> Self {
+        ChunkLists {
+            files: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&self, path: &Path, content: &[u8]) {
+        let mut chunker = Chunker::new();
+        chunker.update(content);
+        self.finish(path, chunker);
+    }
+
+    pub fn finish(&self, path: &Path, chunker: Chunker) {
+        let chunks = chunker.finalize();
+        self.files.lock().push((path.to_owned(), chunks));
+    }
+
+    fn write(self, out: &mut dyn Write, label: &Path, eol: u8) -> Result<()> {
+        let mut files = self.files.into_inner();
+        files.sort_unstable_by(|a, b| a.0.as_os_str().cmp(b.0.as_os_str()));
+        for (path, chunks) in files {
+            let path = label.join(path);
+            let (prefix, path) = manifest::escape(&path, eol);
+            for chunk in chunks {
+                out.write_all(prefix)?;
+                write!(
+                    out,
+                    "{}  {}  {}  ",
+                    Hex(&chunk.digest),
+                    chunk.offset,
+                    chunk.len,
+                )?;
+                out.write_all(&path)?;
+                out.write_all(&[eol])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn write(path: &Path, lists: Vec<(&Path, ChunkLists)>, eol: u8) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for (label, list) in lists {
+        list.write(&mut out, label, eol)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// For content that is read a piece at a time. Boundaries do not depend on
+/// how the content is divided into pieces.
+pub struct Chunker {
+    sha: Sha1,
+    hash: u64,
+    offset: u64,
+    len: u64,
+    chunks: Vec<Chunk>,
+}
+
+impl Chunker {
+    pub fn new() -> Self {
+        Chunker {
+            sha: Sha1::new(),
+            hash: 0,
+            offset: 0,
+            len: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            // Nothing before the minimum size can be a boundary.
+            if self.len < MIN_SIZE {
+                let n = cmp::min(MIN_SIZE - self.len, bytes.len() as u64) as usize;
+                self.sha.update(&bytes[..n]);
+                self.len += n as u64;
+                bytes = &bytes[n..];
+                continue;
+            }
+
+            let mut boundary = None;
+            for (i, &byte) in bytes.iter().enumerate() {
+                self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+                let len = self.len + i as u64 + 1;
+                let mask = if len < AVG_SIZE {
+                    MASK_SMALL
+                } else {
+                    MASK_LARGE
+                };
+                if self.hash & mask == 0 || len == MAX_SIZE {
+                    boundary = Some(i + 1);
+                    break;
+                }
+            }
+
+            let n = boundary.unwrap_or(bytes.len());
+            self.sha.update(&bytes[..n]);
+            self.len += n as u64;
+            bytes = &bytes[n..];
+            if boundary.is_some() {
+                self.cut();
+            }
+        }
+    }
+
+    fn cut(&mut self) {
+        self.chunks.push(Chunk {
+            offset: self.offset,
+            len: self.len,
+            digest: self.sha.finalize_reset().into(),
+        });
+        self.offset += self.len;
+        self.len = 0;
+        self.hash = 0;
+    }
+
+    fn finalize(mut self) -> Vec<Chunk> {
+        if self.len > 0 {
+            self.cut();
+        }
+        self.chunks
+    }
+}
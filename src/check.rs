@@ -0,0 +1,221 @@
+//! Verification of directories against a manifest previously written by
+//! --manifest, optionally signed with --sign or --gpg-sign.
+//!
+//! With --verify-sample, each entry is included in the sample according to a
+//! hash of its path and the seed, so that repeated runs with the same seed
+//! check the same entries and different seeds eventually cover all of them.
+
+use crate::audit::AuditLog;
+use crate::dirfd::Dir;
+use crate::manifest::{self, Entry, Manifest};
+use crate::{
+    canonicalize, ct_eq, die, diff, gpg, manifest_of, open_audit_log, sign, single_entry,
+    write_atomic, Context, Limits, Opt,
+};
+use parking_lot::Mutex;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use sha1::{Digest, Sha1};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process;
+
+pub fn run(opt: &Opt, path: &Path) -> ! {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(error) => die(path, error),
+    };
+    let (mut listing, signature) = sign::split_signature(&content, opt.eol());
+
+    if let Some(public_key) = &opt.verify_signature {
+        let Some(signature) = signature else {
+            die(path, "manifest is not signed");
+        };
+        if let Err(error) = sign::verify(public_key, listing, signature) {
+            die(path, format_args!("bad signature: {}", error));
+        }
+    }
+
+    if opt.gpg_verify {
+        let Some(signature) = signature else {
+            die(path, "manifest is not signed");
+        };
+        if let Err(error) = gpg::verify(listing, signature) {
+            die(path, error);
+        }
+    }
+
+    let expected = match manifest::parse(&mut listing, opt.eol()) {
+        Ok(expected) => expected,
+        Err(error) => die(path, error),
+    };
+
+    // Up front, so that a directory that is missing is reported before any
+    // is hashed.
+    let canonical: Vec<_> = expected
+        .iter()
+        .map(|manifest| canonicalize(&manifest.label))
+        .collect();
+
+    let audit_log = open_audit_log(opt);
+    let mut differ = false;
+    for (expected, canonical) in expected.iter().zip(&canonical) {
+        differ |= if let Some(percent) = opt.verify_sample {
+            let sample = Sample {
+                percent,
+                seed: opt.sample_seed,
+            };
+            check_sample(opt, expected, canonical, sample, audit_log.as_ref())
+        } else {
+            let actual = manifest_of(opt, &expected.label, canonical, audit_log.as_ref());
+            check(opt, expected, &actual)
+        };
+    }
+    process::exit(i32::from(differ));
+}
+
+/// For `sha1dir update`. The manifest is replaced only once every directory
+/// has been hashed.
+pub fn update(opt: &Opt, path: &Path) -> ! {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(error) => die(path, error),
+    };
+    let (mut listing, signature) = sign::split_signature(&content, opt.eol());
+    if signature.is_some() {
+        die(path, "cannot update a signed manifest");
+    }
+
+    let expected = match manifest::parse(&mut listing, opt.eol()) {
+        Ok(expected) => expected,
+        Err(error) => die(path, error),
+    };
+
+    // Up front, so that a directory that is missing is reported before any
+    // is hashed.
+    let canonical: Vec<_> = expected
+        .iter()
+        .map(|manifest| canonicalize(&manifest.label))
+        .collect();
+
+    let audit_log = open_audit_log(opt);
+    let mut updated = Vec::new();
+    for (expected, canonical) in expected.iter().zip(&canonical) {
+        let actual = manifest_of(opt, &expected.label, canonical, audit_log.as_ref());
+        check(opt, expected, &actual);
+        if let Err(error) = manifest::write(&mut updated, &actual, opt.eol()) {
+            die(path, error);
+        }
+    }
+
+    if let Err(error) = write_atomic(path, &updated) {
+        die(path, error);
+    }
+    process::exit(0);
+}
+
+#[derive(Copy, Clone)]
+struct Sample {
+    percent: f64,
+    seed: u64,
+}
+
+impl Sample {
+    fn contains(self, path: &Path) -> bool {
+        let mut sha = Sha1::new();
+        sha.update(self.seed.to_le_bytes());
+        sha.update(path.as_os_str().as_bytes());
+        let hash = u64::from_be_bytes(sha.finalize()[..8].try_into().unwrap());
+        ((hash % 1_000_000) as f64) < self.percent * 10_000.0
+    }
+}
+
+// Only entries listed in the manifest are sampled, so an extra entry is never
+// detected, and a directory's entry is checked without its contents.
+fn check_sample(
+    opt: &Opt,
+    expected: &Manifest,
+    canonical: &Path,
+    sample: Sample,
+    audit_log: Option<&AuditLog>,
+) -> bool {
+    let sampled: Vec<Entry> = expected
+        .entries
+        .iter()
+        .filter(|entry| sample.contains(&entry.path))
+        .map(|entry| Entry {
+            path: entry.path.clone(),
+            digest: entry.digest,
+        })
+        .collect();
+
+    if !canonical.is_dir() {
+        die(&expected.label, "--verify-sample requires a directory");
+    }
+    let root = match Dir::Cwd.open_dir(canonical) {
+        Ok(root) => root,
+        Err(error) => die(&expected.label, error),
+    };
+
+    let limits = Limits::new(opt);
+    let mut cx = Context::new(opt, &expected.label, canonical, None, &limits);
+    cx.audit_log = audit_log;
+    cx.entries = Some(Mutex::new(Vec::new()));
+    sampled.par_iter().for_each(|entry| {
+        if let Err(error) = single_entry(&cx, &root, &entry.path) {
+            // Left out of the actual entries, which reports it missing.
+            if let Some(error) = error.downcast_ref::<io::Error>() {
+                if error.kind() == io::ErrorKind::NotFound
+                    || error.raw_os_error() == Some(libc::ENOTDIR)
+                {
+                    return;
+                }
+            }
+            die(expected.label.join(&entry.path), error);
+        }
+    });
+    let mut actual = cx.entries.unwrap().into_inner();
+    manifest::sort(&mut actual);
+
+    let differences = diff::compare(&sampled, &actual);
+    diff::print(&expected.label, &differences, opt.style());
+
+    let total = expected.entries.len();
+    let checked = sampled.len();
+    let differ = differences.len();
+    let estimate = if checked == 0 {
+        String::new()
+    } else if differ == 0 {
+        // Largest fraction of differing entries for which a sample this size
+        // would find none at least 5% of the time.
+        let bound = 1.0 - 0.05f64.powf(1.0 / checked as f64);
+        format!(
+            "; with 95% confidence fewer than {:.2}% of entries differ",
+            bound * 100.0,
+        )
+    } else {
+        let estimate = differ as f64 * total as f64 / checked as f64;
+        format!("; an estimated {:.0} entries differ in total", estimate)
+    };
+    let _ = writeln!(
+        io::stderr(),
+        "{}: sampled {} of {} entries, {} differ{}",
+        expected.label.display(),
+        checked,
+        total,
+        differ,
+        estimate,
+    );
+
+    differ > 0
+}
+
+fn check(opt: &Opt, expected: &Manifest, actual: &Manifest) -> bool {
+    if ct_eq(&expected.checksum, &actual.checksum) {
+        return false;
+    }
+    let differences = diff::compare(&expected.entries, &actual.entries);
+    diff::print(&expected.label, &differences, opt.style());
+    !differences.is_empty()
+}
@@ -0,0 +1,49 @@
+//! Checksums matching ad hoc shell pipelines, for comparison against digests
+//! recorded before sha1dir was in use.
+
+use crate::{die, map, Hex, Result};
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+
+/// Same as `find . -type f -print0 | LC_ALL=C sort -z | xargs -0 cat |
+/// sha1sum` run from inside `dir`.
+pub fn cat_pipeline(dir: &Path) -> String {
+    let mut files = Vec::new();
+    if let Err(error) = find_files(dir, Path::new(""), &mut files) {
+        die(dir, error);
+    }
+
+    // Every path printed by find starts with "./", so sorting the relative
+    // paths bytewise gives the same order.
+    files.sort_unstable_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+
+    let mut sha = Sha1::new();
+    for file in files {
+        let path = dir.join(file);
+        match map(&path) {
+            Ok(mmap) => sha.update(mmap.as_deref().unwrap_or_default()),
+            Err(error) => die(path, error),
+        }
+    }
+    Hex(&sha.finalize()).to_string()
+}
+
+// Like find without -L, symlinks are neither followed nor listed.
+fn find_files(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let path = dir.join(relative);
+    let entries = match path.read_dir() {
+        Ok(entries) => entries,
+        Err(error) => die(path, error),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let relative = relative.join(entry.file_name());
+        if file_type.is_file() {
+            files.push(relative);
+        } else if file_type.is_dir() {
+            find_files(dir, &relative, files)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//! Reader for the SVR4 "newc" cpio format, with or without CRC, as produced by
+//! `cpio -H newc` and used for Linux initramfs images.
+//!
+//! Initramfs images are often several archives concatenated, for example
+//! early microcode followed by the real root filesystem, so reading continues
+//! after each `TRAILER!!!` entry. Compressed images need to be decompressed
+//! first.
+
+use crate::archive::{normalize, Content, Item, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use crate::{die, Context, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+
+const HEADER_LEN: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+pub fn is_cpio(archive: &[u8]) -> bool {
+    archive.starts_with(b"070701") || archive.starts_with(b"070702")
+}
+
+// Paths of every link to an inode, and the content of the one that has it.
+type Links = HashMap<((u64, u64), u64), (Vec<PathBuf>, Option<Content>)>;
+
+struct Header {
+    ino: u64,
+    mode: u32,
+    nlink: u64,
+    filesize: usize,
+    dev: (u64, u64),
+    namesize: usize,
+}
+
+pub fn read_items(cx: &Context, archive: &[u8]) -> Result<HashMap<PathBuf, Item>> {
+    let mut items = HashMap::new();
+
+    // In newc only the last of a set of hard links carries the content; the
+    // other links have size 0. Group them by inode to share that content.
+    let mut links = Links::new();
+
+    let mut offset = 0;
+    while offset < archive.len() {
+        // Padding between concatenated archives.
+        if archive[offset] == b'\0' {
+            offset += 1;
+            continue;
+        }
+
+        let header = parse_header(&archive[offset..])?;
+        let name_start = offset + HEADER_LEN;
+        let name_end = name_start + header.namesize;
+        let data_start = align4(name_end);
+        let data_end = data_start + header.filesize;
+        if data_end > archive.len() {
+            return Err("unexpected end of archive".into());
+        }
+        offset = align4(data_end);
+
+        let name = &archive[name_start..name_end];
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        if name == TRAILER {
+            // Inode numbers are only unique within one archive.
+            share_links(&mut items, &mut links);
+            continue;
+        }
+        let Some(path) = normalize(Path::new(OsStr::from_bytes(name)))? else {
+            continue;
+        };
+
+        let mode = header.mode;
+        let content = Content::Range(data_start..data_end);
+        let item = match mode & S_IFMT {
+            S_IFREG if header.nlink > 1 => {
+                let (paths, shared) = links.entry((header.dev, header.ino)).or_default();
+                paths.push(path.clone());
+                if header.filesize > 0 {
+                    *shared = Some(content);
+                }
+                Item::File {
+                    mode,
+                    content: Content::Range(0..0),
+                }
+            }
+            S_IFREG => Item::File { mode, content },
+            S_IFDIR => Item::Dir { mode },
+            S_IFLNK => Item::Symlink {
+                target: PathBuf::from(OsStr::from_bytes(&archive[data_start..data_end])),
+            },
+            S_IFSOCK => Item::Socket { mode },
+            _ if cx.ignore_unknown_filetypes => continue,
+            _ => die(cx.label.join(path), "Unsupported file type"),
+        };
+        items.insert(path, item);
+    }
+
+    share_links(&mut items, &mut links);
+    Ok(items)
+}
+
+fn share_links(items: &mut HashMap<PathBuf, Item>, links: &mut Links) {
+    for (paths, shared) in links.drain().map(|(_inode, links)| links) {
+        let Some(Content::Range(range)) = shared else {
+            continue;
+        };
+        for path in paths {
+            if let Some(Item::File { content, .. }) = items.get_mut(&path) {
+                *content = Content::Range(range.clone());
+            }
+        }
+    }
+}
+
+fn parse_header(header: &[u8]) -> Result<Header> {
+    if !is_cpio(header) {
+        return Err("unrecognized cpio header; only the newc format is supported".into());
+    }
+    let Some(header) = header.get(..HEADER_LEN) else {
+        return Err("unexpected end of archive".into());
+    };
+    let field = |i: usize| -> Result<u64> {
+        let hex = &header[6 + 8 * i..14 + 8 * i];
+        str::from_utf8(hex)
+            .ok()
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| "malformed cpio header".into())
+    };
+    Ok(Header {
+        ino: field(0)?,
+        mode: field(1)? as u32,
+        nlink: field(4)?,
+        filesize: field(6)? as usize,
+        dev: (field(7)?, field(8)?),
+        namesize: field(11)? as usize,
+    })
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[test]
+fn test_hard_links() {
+    use crate::{Limits, Opt};
+    use clap::Parser;
+
+    fn entry(archive: &mut Vec<u8>, ino: u64, nlink: u64, name: &str, data: &[u8]) {
+        let mode = u64::from(S_IFREG | 0o644);
+        let namesize = name.len() as u64 + 1;
+        let fields = [
+            ino,
+            mode,
+            0,
+            0,
+            nlink,
+            0,
+            data.len() as u64,
+            0,
+            1,
+            0,
+            0,
+            namesize,
+            0,
+        ];
+        archive.extend_from_slice(b"070701");
+        for field in fields {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(b'\0');
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(data);
+        archive.resize(align4(archive.len()), 0);
+    }
+
+    // Concatenated archives that reuse an inode number, the way an initramfs
+    // with early microcode does.
+    let mut archive = Vec::new();
+    entry(&mut archive, 1, 2, "a", b"");
+    entry(&mut archive, 2, 1, "c", b"own");
+    entry(&mut archive, 1, 2, "b", b"shared");
+    entry(&mut archive, 3, 2, "lone", b"");
+    entry(&mut archive, 0, 1, "TRAILER!!!", b"");
+    entry(&mut archive, 1, 2, "d", b"");
+    entry(&mut archive, 1, 2, "e", b"second");
+    entry(&mut archive, 0, 1, "TRAILER!!!", b"");
+
+    let opt = Opt::parse_from(["sha1dir"]);
+    let limits = Limits::new(&opt);
+    let cx = Context::new(&opt, Path::new("x"), Path::new("x"), None, &limits);
+    let items = read_items(&cx, &archive).unwrap();
+    let content = |path: &str| match &items[Path::new(path)] {
+        Item::File {
+            content: Content::Range(range),
+            ..
+        } => &archive[range.clone()],
+        _ => panic!("{} is not in the archive as a file", path),
+    };
+    assert_eq!(content("a"), b"shared");
+    assert_eq!(content("b"), b"shared");
+    assert_eq!(content("c"), b"own");
+    assert_eq!(content("d"), b"second");
+    assert_eq!(content("e"), b"second");
+    assert_eq!(content("lone"), b"");
+    assert_eq!(items.len(), 6);
+}
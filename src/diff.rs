@@ -0,0 +1,218 @@
+//! Entry-by-entry comparison of two manifests.
+
+use crate::manifest::{Entry, Manifest};
+use crate::{canonicalize, ct_eq, die, manifest_of, open_audit_log, Diff, Opt, Report, Result};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process;
+
+pub fn run(opt: &Opt, args: &Diff) -> ! {
+    let expected_canonical = canonicalize(&args.expected);
+    let actual_canonical = canonicalize(&args.actual);
+    let audit_log = open_audit_log(opt);
+    let expected = manifest_of(opt, &args.expected, &expected_canonical, audit_log.as_ref());
+    let actual = manifest_of(opt, &args.actual, &actual_canonical, audit_log.as_ref());
+    report(&expected, &actual, &args.report, opt.style());
+}
+
+/// Prints the differences and exits with status 1 if there are any.
+pub fn report(expected: &Manifest, actual: &Manifest, options: &Report, style: Style) -> ! {
+    let differences = if ct_eq(&expected.checksum, &actual.checksum) {
+        Vec::new()
+    } else {
+        compare(&expected.entries, &actual.entries)
+    };
+
+    // Written even when empty, so that a list left over from an earlier run
+    // is never mistaken for the current one.
+    if let Some(path) = &options.repair_list {
+        if let Err(error) = write_repair_list(path, &differences, style.eol) {
+            die(path, error);
+        }
+    }
+
+    if let Some(path) = &options.emit_rsync_filter {
+        if let Err(error) = write_rsync_filter(path, &differences) {
+            die(path, error);
+        }
+    }
+
+    print(Path::new(""), &differences, style);
+    process::exit(i32::from(!differences.is_empty()));
+}
+
+// Paths are relative to the top of both trees, which is what rsync expects
+// with `--files-from` given the expected tree as the source. With -z they are
+// separated by NUL bytes instead, for `--from0`.
+fn write_repair_list(path: &Path, differences: &[Difference], eol: u8) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for difference in differences {
+        let path = match difference {
+            Difference::Changed(path) | Difference::Missing(path) => path,
+            Difference::Extra(_) => continue,
+        };
+        if eol == b'\n' {
+            check_no_newline(path)?;
+        }
+        out.write_all(path.as_os_str().as_bytes())?;
+        out.write_all(&[eol])?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn check_no_newline(path: &Path) -> Result<()> {
+    if path.as_os_str().as_bytes().contains(&b'\n') {
+        let path = path.to_string_lossy();
+        return Err(format!("cannot list path containing a newline: {:?}", path).into());
+    }
+    Ok(())
+}
+
+// Every ancestor directory of a differing entry must be included for rsync to
+// descend into it, and everything else is excluded. A missing or extra
+// directory is included together with its contents as a single rule.
+fn write_rsync_filter(path: &Path, differences: &[Difference]) -> Result<()> {
+    let mut rules = BTreeSet::new();
+    let mut covered: Option<&Path> = None;
+    for difference in differences {
+        let path = difference.path();
+        if covered.is_some_and(|covered| path.starts_with(covered)) {
+            continue;
+        }
+        check_no_newline(path)?;
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.as_os_str().is_empty() {
+                rules.insert(rule(ancestor, b"/"));
+            }
+        }
+        rules.insert(rule(path, b""));
+        if let Difference::Missing(path) | Difference::Extra(path) = difference {
+            rules.insert(rule(path, b"/***"));
+            covered = Some(path);
+        }
+    }
+
+    let mut out = BufWriter::new(File::create(path)?);
+    for rule in rules {
+        out.write_all(b"+ /")?;
+        out.write_all(&rule)?;
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b"- *\n")?;
+    out.flush()?;
+    Ok(())
+}
+
+// rsync only treats backslash as an escape in patterns which contain a
+// wildcard, so paths without wildcards are used verbatim.
+fn rule(path: &Path, suffix: &[u8]) -> Vec<u8> {
+    let path = path.as_os_str().as_bytes();
+    let is_wildcard = |b: &u8| matches!(b, b'*' | b'?' | b'[');
+    let mut rule = Vec::with_capacity(path.len() + suffix.len());
+    if path.iter().any(is_wildcard) || suffix.iter().any(is_wildcard) {
+        for &b in path {
+            if is_wildcard(&b) || b == b'\\' {
+                rule.push(b'\\');
+            }
+            rule.push(b);
+        }
+    } else {
+        rule.extend_from_slice(path);
+    }
+    rule.extend_from_slice(suffix);
+    rule
+}
+
+pub enum Difference<'a> {
+    /// Present on both sides with different digests.
+    Changed(&'a Path),
+    /// Present only in the expected tree.
+    Missing(&'a Path),
+    /// Present only in the actual tree.
+    Extra(&'a Path),
+}
+
+impl<'a> Difference<'a> {
+    pub fn path(&self) -> &'a Path {
+        match self {
+            Difference::Changed(path) | Difference::Missing(path) | Difference::Extra(path) => path,
+        }
+    }
+
+    fn marker(&self) -> &'static str {
+        match self {
+            Difference::Changed(_) => "changed",
+            Difference::Missing(_) => "missing",
+            Difference::Extra(_) => "extra",
+        }
+    }
+}
+
+/// Both sides must be sorted by path, as by `manifest::sort`.
+pub fn compare<'a>(expected: &'a [Entry], actual: &'a [Entry]) -> Vec<Difference<'a>> {
+    let mut differences = Vec::new();
+    let mut expected = expected.iter().peekable();
+    let mut actual = actual.iter().peekable();
+    loop {
+        let ordering = match (expected.peek(), actual.peek()) {
+            (Some(e), Some(a)) => e.path.as_os_str().cmp(a.path.as_os_str()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return differences,
+        };
+        match ordering {
+            Ordering::Less => {
+                let e = expected.next().unwrap();
+                differences.push(Difference::Missing(&e.path));
+            }
+            Ordering::Greater => {
+                let a = actual.next().unwrap();
+                differences.push(Difference::Extra(&a.path));
+            }
+            Ordering::Equal => {
+                let e = expected.next().unwrap();
+                let a = actual.next().unwrap();
+                if !ct_eq(&e.digest, &a.digest) {
+                    differences.push(Difference::Changed(&e.path));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Style {
+    pub eol: u8,
+    pub color: bool,
+}
+
+/// Paths are printed relative to `dir`, which is empty to print them as is.
+pub fn print(dir: &Path, differences: &[Difference], style: Style) {
+    let mut stdout = io::stdout().lock();
+    for difference in differences {
+        let marker = difference.marker();
+        if style.color {
+            let color = match difference {
+                Difference::Changed(_) => YELLOW,
+                Difference::Missing(_) => RED,
+                Difference::Extra(_) => GREEN,
+            };
+            let _ = write!(stdout, "{}{:<8}{} ", color, marker, RESET);
+        } else {
+            let _ = write!(stdout, "{:<8} ", marker);
+        }
+        let path = dir.join(difference.path());
+        let _ = stdout.write_all(path.as_os_str().as_bytes());
+        let _ = stdout.write_all(&[style.eol]);
+    }
+}
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
@@ -0,0 +1,156 @@
+//! Directories held open by file descriptor during traversal.
+//!
+//! Entries are opened, stat'ed and read relative to the descriptor of the
+//! directory that contains them, by name alone, so the kernel does not
+//! resolve the whole path from the current directory again for every entry,
+//! and trees whose paths are longer than `PATH_MAX` can still be hashed.
+//!
+//! An open that fails because the process or system is out of descriptors is
+//! retried once others have had time to be closed, rather than failing the
+//! whole run.
+
+use crate::readdir::ReadDir;
+use crate::stat::{Fields, Stat};
+use std::cmp;
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const RETRY_INITIAL: Duration = Duration::from_millis(1);
+const RETRY_MAX: Duration = Duration::from_millis(100);
+const GIVE_UP: Duration = Duration::from_secs(30);
+
+static OPEN_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+pub enum Dir {
+    /// Names are resolved relative to the current directory, so may be paths.
+    Cwd,
+    Fd(OwnedFd),
+}
+
+/// An entry by its name within a directory.
+#[derive(Copy, Clone)]
+pub struct At<'a> {
+    pub dir: &'a Dir,
+    pub name: &'a Path,
+}
+
+impl Dir {
+    pub fn raw_fd(&self) -> RawFd {
+        match self {
+            Dir::Cwd => libc::AT_FDCWD,
+            Dir::Fd(fd) => fd.as_raw_fd(),
+        }
+    }
+
+    /// Does not follow a symlink.
+    pub fn open_dir(&self, name: &Path) -> io::Result<Dir> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = openat(self.raw_fd(), name, flags)?;
+        OPEN_DIRS.fetch_add(1, Ordering::Relaxed);
+        Ok(Dir::Fd(fd))
+    }
+
+    /// Only for a directory that was opened.
+    pub fn read_dir(&self) -> io::Result<ReadDir<'_>> {
+        match self {
+            Dir::Cwd => unreachable!(),
+            Dir::Fd(fd) => ReadDir::new(fd),
+        }
+    }
+
+    /// Of the directory itself.
+    pub fn stat(&self, fields: Fields) -> io::Result<Stat> {
+        let mut buf = MaybeUninit::<libc::stat>::uninit();
+        if unsafe { libc::fstat(self.raw_fd(), buf.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Stat::from_stat(unsafe { buf.assume_init_ref() }, fields))
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        if let Dir::Fd(_) = self {
+            OPEN_DIRS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Number of directories currently held open.
+pub fn open_dirs() -> usize {
+    OPEN_DIRS.load(Ordering::Relaxed)
+}
+
+impl At<'_> {
+    /// Opens for reading, with any additional flags.
+    pub fn open(self, flags: libc::c_int) -> io::Result<File> {
+        let flags = libc::O_RDONLY | libc::O_CLOEXEC | flags;
+        let fd = openat(self.dir.raw_fd(), self.name, flags)?;
+        Ok(File::from(fd))
+    }
+
+    pub fn read_link(self) -> io::Result<PathBuf> {
+        let name = c_string(self.name)?;
+        let mut buf = Vec::<u8>::with_capacity(256);
+        loop {
+            let len = unsafe {
+                libc::readlinkat(
+                    self.dir.raw_fd(),
+                    name.as_ptr(),
+                    buf.as_mut_ptr().cast(),
+                    buf.capacity(),
+                )
+            };
+            let Ok(len) = usize::try_from(len) else {
+                return Err(io::Error::last_os_error());
+            };
+            // Possibly truncated if it fills the buffer.
+            if len < buf.capacity() {
+                unsafe { buf.set_len(len) };
+                return Ok(PathBuf::from(OsString::from_vec(buf)));
+            }
+            buf.reserve(buf.capacity() * 2);
+        }
+    }
+}
+
+fn openat(dir: RawFd, name: &Path, flags: libc::c_int) -> io::Result<OwnedFd> {
+    let name = c_string(name)?;
+    retry(|| {
+        let fd = unsafe { libc::openat(dir, name.as_ptr(), flags) };
+        if fd == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        }
+    })
+}
+
+fn retry<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let start = Instant::now();
+    let mut wait = RETRY_INITIAL;
+    loop {
+        match f() {
+            Err(error)
+                if matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+                    && start.elapsed() < GIVE_UP =>
+            {
+                thread::sleep(wait);
+                wait = cmp::min(wait * 2, RETRY_MAX);
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn c_string(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
@@ -0,0 +1,75 @@
+//! Counts of what hashing each directory would involve, for --estimate.
+//!
+//! The tree is walked as usual but nothing is read, so this takes about as
+//! long as listing it. The duration is projected from the total size of the
+//! regular files alone, at the rate given by --estimate-rate.
+
+use crate::stat::Stat;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+pub struct Totals {
+    files: AtomicU64,
+    dirs: AtomicU64,
+    other: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Totals {
+    pub fn add(&self, metadata: &Stat) {
+        if metadata.is_file() {
+            self.files.fetch_add(1, Ordering::Relaxed);
+            self.bytes.fetch_add(metadata.size, Ordering::Relaxed);
+        } else if metadata.is_dir() {
+            self.dirs.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.other.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn entries(&self) -> u64 {
+        self.files.load(Ordering::Relaxed)
+            + self.dirs.load(Ordering::Relaxed)
+            + self.other.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+pub fn write(
+    out: &mut dyn Write,
+    totals: Totals,
+    rate: u64,
+    label: Option<&Path>,
+) -> io::Result<()> {
+    let bytes = totals.bytes.into_inner();
+    write!(
+        out,
+        "{} files, {} directories, {} other, {} bytes, {}",
+        totals.files.into_inner(),
+        totals.dirs.into_inner(),
+        totals.other.into_inner(),
+        bytes,
+        duration(bytes / rate),
+    )?;
+    match label {
+        Some(label) => writeln!(out, "  {}", label.display()),
+        None => writeln!(out),
+    }
+}
+
+// Such as 1h02m03s.
+fn duration(secs: u64) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
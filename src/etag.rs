@@ -0,0 +1,126 @@
+//! Entity tags as computed by S3 for objects uploaded by `aws s3 cp` or `aws
+//! s3 sync`.
+//!
+//! An object smaller than the multipart threshold has the MD5 of its content
+//! as its tag. Anything larger is uploaded in parts, and its tag is the MD5 of
+//! the concatenated binary MD5s of each part, followed by `-` and the number
+//! of parts. The awscli defaults use the same value for the threshold and the
+//! part size, which is assumed here.
+
+use crate::manifest;
+use crate::{Hex, Result};
+use md5::{Digest, Md5};
+use parking_lot::Mutex;
+use std::cmp;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub struct Etags {
+    chunk_size: u64,
+    etags: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl Etags {
+    pub fn new(chunk_size: u64) -> Self {
+        Etags {
+            chunk_size,
+            etags: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&self, path: &Path, content: &[u8]) {
+        let mut etag = self.start();
+        etag.update(content);
+        self.finish(path, etag);
+    }
+
+    /// For content that is read a piece at a time.
+    pub fn start(&self) -> Etag {
+        Etag {
+            chunk_size: self.chunk_size,
+            part: Md5::new(),
+            part_len: 0,
+            md5s: Md5::new(),
+            parts: 0,
+        }
+    }
+
+    pub fn finish(&self, path: &Path, etag: Etag) {
+        let etag = etag.finalize();
+        self.etags.lock().push((path.to_owned(), etag));
+    }
+
+    pub fn write(self, out: &mut dyn Write, label: &Path, eol: u8) -> Result<()> {
+        let mut etags = self.etags.into_inner();
+        etags.sort_unstable_by(|a, b| a.0.as_os_str().cmp(b.0.as_os_str()));
+        for (path, etag) in etags {
+            let path = label.join(path);
+            let (prefix, path) = manifest::escape(&path, eol);
+            out.write_all(prefix)?;
+            write!(out, "{}  ", etag)?;
+            out.write_all(&path)?;
+            out.write_all(&[eol])?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Etag {
+    chunk_size: u64,
+    part: Md5,
+    part_len: u64,
+    md5s: Md5,
+    parts: usize,
+}
+
+impl Etag {
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let remaining = self.chunk_size - self.part_len;
+            let n = cmp::min(remaining, bytes.len() as u64) as usize;
+            self.part.update(&bytes[..n]);
+            self.part_len += n as u64;
+            bytes = &bytes[n..];
+            if self.part_len == self.chunk_size {
+                self.md5s.update(self.part.finalize_reset());
+                self.part_len = 0;
+                self.parts += 1;
+            }
+        }
+    }
+
+    fn finalize(mut self) -> String {
+        if self.parts == 0 {
+            return Hex(&self.part.finalize()).to_string();
+        }
+        if self.part_len > 0 {
+            self.md5s.update(self.part.finalize());
+            self.parts += 1;
+        }
+        format!("{}-{}", Hex(&self.md5s.finalize()), self.parts)
+    }
+}
+
+#[test]
+fn test_etag() {
+    // Files of zeros as uploaded by `aws s3 cp` with its default 8 MiB part
+    // size, read in pieces that do not line up with the parts.
+    let etags = Etags::new(8 << 20);
+    let etag = |len: usize, piece: usize| {
+        let mut etag = etags.start();
+        for chunk in vec![0; len].chunks(piece) {
+            etag.update(chunk);
+        }
+        etag.finalize()
+    };
+    assert_eq!(etag(0, 1), "d41d8cd98f00b204e9800998ecf8427e");
+    assert_eq!(etag(1 << 20, 1 << 20), "b6d81b360a5672d80c27430f39153e2c");
+    assert_eq!(
+        etag(16 << 20, 3 << 20),
+        "3a2d20e2e504fe056bbaae5b4c2351fd-2"
+    );
+    assert_eq!(
+        etag(20 << 20, 7 << 20),
+        "5452e5568d20a60209babc69a7b95911-3"
+    );
+}
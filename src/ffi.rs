@@ -0,0 +1,70 @@
+//! C interface, declared in include/sha1dir.h, for the `ffi` feature.
+//!
+//! Built as a shared library by:
+//!
+//! ```console
+//! $ cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+
+use crate::Options;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+#[repr(C)]
+pub struct FfiOptions {
+    threads: usize,
+    ignore_unknown_filetypes: bool,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn sha1dir_hash(
+    path: *const c_char,
+    options: *const FfiOptions,
+    out: *mut u8,
+) -> c_int {
+    // SAFETY: the caller passes a NUL-terminated path, a null or valid
+    // options pointer, and room for 20 bytes at `out`.
+    let path = OsStr::from_bytes(unsafe { CStr::from_ptr(path) }.to_bytes());
+    let options = unsafe { options.as_ref() };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut builder = Options::new();
+        if let Some(options) = options {
+            if options.threads > 0 {
+                builder.threads(options.threads);
+            }
+            builder.ignore_unknown_filetypes(options.ignore_unknown_filetypes);
+        }
+        let checksummer = builder.build().map_err(|error| error.to_string())?;
+        checksummer
+            .checksum(path)
+            .map_err(|errors| errors.to_string())
+    }));
+
+    let error = match result {
+        Ok(Ok(checksum)) => {
+            unsafe { ptr::copy_nonoverlapping(checksum.as_bytes().as_ptr(), out, 20) };
+            return 0;
+        }
+        Ok(Err(error)) => error,
+        Err(_) => "panicked".to_owned(),
+    };
+    let error = CString::new(error.replace('\0', "\\0")).unwrap();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(error));
+    -1
+}
+
+#[no_mangle]
+pub extern "C" fn sha1dir_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(error) => error.as_ptr(),
+        None => ptr::null(),
+    })
+}
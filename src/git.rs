@@ -0,0 +1,157 @@
+//! Comparison of a git working tree against the blobs recorded in HEAD.
+//!
+//! Every tracked path is hashed as a git blob in parallel and compared to
+//! `git ls-tree -r HEAD`. Files that git would transform on checkout (CRLF
+//! conversion, clean/smudge filters such as LFS) show up as changed even when
+//! `git status` considers them clean.
+
+use crate::diff::{self, Difference};
+use crate::{die, map, parse_hex, GitVerify, Mmap, Opt, Result};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use sha1::{Digest, Sha1};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+enum Mode {
+    File,
+    Executable,
+    Symlink,
+}
+
+struct Blob {
+    path: PathBuf,
+    mode: Mode,
+    id: [u8; 20],
+}
+
+pub fn run(opt: &Opt, args: &GitVerify) -> ! {
+    let toplevel = git(&args.repo, &["rev-parse", "--show-toplevel"]);
+    let toplevel = toplevel.strip_suffix(b"\n").unwrap_or(&toplevel);
+    let toplevel = Path::new(OsStr::from_bytes(toplevel));
+
+    let tree = git(toplevel, &["ls-tree", "-r", "-z", "--full-tree", "HEAD"]);
+    let blobs = match parse_tree(&tree) {
+        Ok(blobs) => blobs,
+        Err(error) => die(toplevel, error),
+    };
+
+    let mut differences: Vec<Difference> = blobs
+        .par_iter()
+        .filter_map(|blob| match verify(toplevel, blob) {
+            Ok(true) => None,
+            Ok(false) => Some(Difference::Changed(&blob.path)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                Some(Difference::Missing(&blob.path))
+            }
+            Err(error) => die(toplevel.join(&blob.path), error),
+        })
+        .collect();
+
+    let untracked = git(
+        toplevel,
+        &["ls-files", "-z", "--others", "--exclude-standard"],
+    );
+    for path in untracked
+        .split(|&b| b == b'\0')
+        .filter(|path| !path.is_empty())
+    {
+        differences.push(Difference::Extra(Path::new(OsStr::from_bytes(path))));
+    }
+
+    differences.sort_by(|a, b| a.path().as_os_str().cmp(b.path().as_os_str()));
+    diff::print(Path::new(""), &differences, opt.style());
+    process::exit(i32::from(!differences.is_empty()));
+}
+
+fn git(dir: &Path, args: &[&str]) -> Vec<u8> {
+    let output = match Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(error) => die("git", error),
+    };
+    if !output.status.success() {
+        die(dir, format_args!("git {} {}", args[0], output.status));
+    }
+    output.stdout
+}
+
+fn parse_tree(tree: &[u8]) -> Result<Vec<Blob>> {
+    let mut blobs = Vec::new();
+    for line in tree.split(|&b| b == b'\0').filter(|line| !line.is_empty()) {
+        let malformed = || {
+            format!(
+                "unexpected ls-tree output: {}",
+                String::from_utf8_lossy(line)
+            )
+        };
+        let tab = line
+            .iter()
+            .position(|&b| b == b'\t')
+            .ok_or_else(malformed)?;
+        let (info, path) = (&line[..tab], &line[tab + 1..]);
+        let mut info = info.split(|&b| b == b' ');
+        let (Some(mode), Some(kind), Some(id)) = (info.next(), info.next(), info.next()) else {
+            return Err(malformed().into());
+        };
+        let mode = match (mode, kind) {
+            (b"100644", b"blob") => Mode::File,
+            (b"100755", b"blob") => Mode::Executable,
+            (b"120000", b"blob") => Mode::Symlink,
+            // Submodules are checked out separately.
+            (b"160000", b"commit") => continue,
+            _ => return Err(malformed().into()),
+        };
+        let id = parse_hex(id).ok_or_else(malformed)?;
+        let path = PathBuf::from(OsStr::from_bytes(path));
+        blobs.push(Blob { path, mode, id });
+    }
+    Ok(blobs)
+}
+
+fn verify(toplevel: &Path, blob: &Blob) -> io::Result<bool> {
+    let path = toplevel.join(&blob.path);
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        // A tracked file whose parent has been replaced by a file.
+        Err(error) if error.raw_os_error() == Some(libc::ENOTDIR) => {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Err(error) => return Err(error),
+    };
+
+    let file_type = metadata.file_type();
+    let executable = metadata.permissions().mode() & 0o100 != 0;
+    let content = match blob.mode {
+        Mode::File | Mode::Executable if !file_type.is_file() => return Ok(false),
+        Mode::File if executable => return Ok(false),
+        Mode::Executable if !executable => return Ok(false),
+        Mode::Symlink if !file_type.is_symlink() => return Ok(false),
+        Mode::File | Mode::Executable => Content::Mapped(map(&path)?),
+        Mode::Symlink => Content::Target(fs::read_link(&path)?),
+    };
+
+    let bytes = match &content {
+        Content::Mapped(mmap) => mmap.as_deref().unwrap_or_default(),
+        Content::Target(target) => target.as_os_str().as_bytes(),
+    };
+    let mut sha = Sha1::new();
+    sha.update(format!("blob {}\0", bytes.len()));
+    sha.update(bytes);
+    Ok(sha.finalize()[..] == blob.id)
+}
+
+enum Content {
+    Mapped(Option<Mmap>),
+    Target(PathBuf),
+}
@@ -0,0 +1,63 @@
+//! GPG signature appended to the output by running gpg, for tooling that
+//! accepts nothing else.
+//!
+//! The ASCII-armored detached signature covers every byte of output before
+//! it, as with --sign. Keys, passphrases and trust are left entirely to gpg
+//! and its agent.
+
+use crate::Result;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::{self, Command, Stdio};
+
+pub const ARMOR_HEADER: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+
+pub fn sign(key_id: &str, output: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new("gpg")
+        .args(["--detach-sign", "--armor", "--output", "-", "--local-user"])
+        .arg(key_id)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("gpg: {}", error))?;
+    child.stdin.take().unwrap().write_all(output)?;
+    let result = child.wait_with_output()?;
+    if !result.status.success() {
+        return Err(format!("gpg --detach-sign {}", result.status).into());
+    }
+    Ok(result.stdout)
+}
+
+pub fn verify(signed: &[u8], signature: &[u8]) -> Result<()> {
+    // gpg reads a detached signature only from a file, with the signed data on
+    // stdin.
+    let path = env::temp_dir().join(format!("sha1dir-{}.asc", process::id()));
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    let result = file
+        .write_all(signature)
+        .map_err(Into::into)
+        .and_then(|()| run_verify(&path, signed));
+    let _ = fs::remove_file(&path);
+    result
+}
+
+fn run_verify(signature: &Path, signed: &[u8]) -> Result<()> {
+    let mut child = Command::new("gpg")
+        .arg("--verify")
+        .arg(signature)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|error| format!("gpg: {}", error))?;
+    child.stdin.take().unwrap().write_all(signed)?;
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("gpg --verify {}", status).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,990 @@
+//! [![github]](https://github.com/dtolnay/sha1dir)&ensp;[![crates-io]](https://crates.io/crates/sha1dir)&ensp;[![docs-rs]](https://docs.rs/sha1dir)
+//!
+//! [github]: https://img.shields.io/badge/github-8da0cb?style=for-the-badge&labelColor=555555&logo=github
+//! [crates-io]: https://img.shields.io/badge/crates.io-fc8d62?style=for-the-badge&labelColor=555555&logo=rust
+//! [docs-rs]: https://img.shields.io/badge/docs.rs-66c2a5?style=for-the-badge&labelColor=555555&logo=docs.rs
+//!
+//! Checksum of a directory tree, the same as computed by the `sha1dir`
+//! command, for use from other programs.
+//!
+//! Nothing here exits the process or prints. An entry that cannot be hashed
+//! is recorded and the rest of the tree is hashed regardless, so that all of
+//! the failures are reported together.
+//!
+//! ```no_run
+//! let checksum = sha1dir::Checksummer::new().checksum("/srv/data")?;
+//! println!("{}", checksum);
+//! # Ok::<(), sha1dir::Errors>(())
+//! ```
+//!
+//! Options corresponding to flags of the command are set through [`Options`],
+//! whose [`build`][Options::build] gives a `Checksummer` to hash any number
+//! of directories with.
+//!
+//! ```no_run
+//! let checksummer = sha1dir::Options::new()
+//!     .threads(4)
+//!     .ignore_unknown_filetypes(true)
+//!     .build()?;
+//! for dir in ["/srv/data", "/srv/backup"] {
+//!     println!("{}  {}", checksummer.checksum(dir)?, dir);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Only the command needs the default `cli` feature, so programs using the
+//! library can depend on sha1dir with `default-features = false` and build
+//! without an argument parser and the rest of the command's dependencies.
+//!
+//! The library, unlike the command, also builds for WASI. There entries have
+//! no mode, so the one hashed is made up from the file type and whether the
+//! entry is read-only, and sockets are indistinguishable from other unknown
+//! file types.
+
+#![cfg_attr(not(any(feature = "ffi", feature = "python")), forbid(unsafe_code))]
+#![allow(
+    clippy::cast_possible_truncation,
+    clippy::module_name_repetitions,
+    clippy::uninlined_format_args
+)]
+
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "serde")]
+mod serde;
+#[cfg(feature = "tokio")]
+mod tokio;
+
+use parking_lot::Mutex;
+use rayon::{Scope, ThreadPool, ThreadPoolBuilder};
+use sha1::{Digest, Sha1};
+use std::error::Error as StdError;
+use std::fmt::{self, Debug, Display};
+use std::fs::{self, File, FileType, Metadata};
+use std::hint;
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::str::{self, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::vec;
+
+/// The first layout of the bytes hashed for each entry, which is the same as
+/// the `sha1dir` command's without flags that change it.
+///
+/// Each entry's digest is the SHA-1 of: one byte for its kind, `'f'` for a
+/// regular file, `'d'` for a directory, `'l'` for a symlink or `'s'` for a
+/// socket; 4 little endian bytes for the length of its path relative to the
+/// directory being hashed, and the bytes of that path, with `/` between
+/// components; and 4 little endian bytes for its Unix file mode as given by
+/// `st_mode`. A regular file's content follows, and a symlink's target. The
+/// checksum is the XOR of the digests of every entry but the directory
+/// itself.
+///
+/// The checksum of a directory under a given version never changes between
+/// releases. A release that changes the layout does so as a new version, and
+/// keeps the old ones selectable through [`Options::format_version`] and
+/// `--format-version`.
+pub const FORMAT_V1: u32 = 1;
+
+/// The latest layout, which is the one used unless another is selected.
+pub const FORMAT_VERSION: u32 = FORMAT_V1;
+
+const BUFFER_LEN: usize = 64 << 10;
+
+// Entries hashed ahead of the consumer of an `Entries` iterator.
+const STREAM_CAPACITY: usize = 1024;
+
+type Filter = Arc<Mutex<dyn FnMut(&Path, &Metadata) -> Decision + Send>>;
+type Visitor = Arc<Mutex<dyn FnMut(&Path, Checksum) + Send>>;
+type UnknownHandler = Arc<Mutex<dyn FnMut(&Path, &Metadata, EntryKind) -> UnknownAction + Send>>;
+
+/// How to hash directories, as given by flags of the `sha1dir` command.
+#[derive(Clone, Default)]
+pub struct Options {
+    pool: Option<Pool>,
+    ignore_unknown_filetypes: bool,
+    format_version: Option<u32>,
+    cancel: Option<Arc<AtomicBool>>,
+    filter: Option<Filter>,
+    visitor: Option<Visitor>,
+    unknown: Option<UnknownHandler>,
+    encoder: Option<Arc<dyn EncodeMetadata>>,
+}
+
+#[derive(Clone, Debug)]
+enum Pool {
+    Threads(usize),
+    Shared(Arc<ThreadPool>),
+}
+
+/// Whether to hash an entry, as decided by a [filter][Options::filter].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Hash the entry.
+    Include,
+    /// Leave out the entry, and everything under it if a directory, as if it
+    /// did not exist.
+    Exclude,
+}
+
+/// What to do with an entry that is not a regular file, directory, symlink
+/// or socket, as decided by an [unknown filetype
+/// handler][Options::unknown_filetypes].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnknownAction {
+    /// Fail with [`Error::UnsupportedFileType`], as by default.
+    Fail,
+    /// Leave out the entry, as with
+    /// [`ignore_unknown_filetypes`][Options::ignore_unknown_filetypes].
+    Skip,
+    /// Hash the entry's path and mode the same as a socket's, with the kind
+    /// byte `'s'`. The mode tells them apart.
+    Hash,
+}
+
+/// Computes checksums of directories.
+#[derive(Clone, Debug, Default)]
+pub struct Checksummer {
+    options: Options,
+    pool: Option<Arc<ThreadPool>>,
+}
+
+/// Checksum of a directory, as printed by `sha1dir`, or the digest of one
+/// entry in it.
+///
+/// Parsed from and displayed as 40 lowercase hex digits. With the `serde`
+/// feature, it is serialized the same way.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Checksum {
+    bytes: [u8; 20],
+}
+
+/// One entry of a directory, as hashed into its checksum.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    path: PathBuf,
+    kind: EntryKind,
+    digest: Checksum,
+    size: u64,
+}
+
+/// Type of an entry of a directory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Socket,
+    /// A named pipe.
+    Fifo,
+    /// A block or character device.
+    Device,
+    /// Anything else, such as a door on illumos, or anything but a file,
+    /// directory or symlink where the file type cannot tell, as on WASI.
+    Unknown,
+}
+
+/// Iterator over the entries of a directory as they are hashed, from
+/// [`Checksummer::entries`].
+pub struct Entries {
+    receiver: Receiver<Result<Entry, Error>>,
+}
+
+/// Error parsing a [`Checksum`] that is not 40 hex digits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ParseChecksumError {
+    _private: (),
+}
+
+/// Every entry that could not be hashed.
+pub struct Errors {
+    errors: Vec<Error>,
+}
+
+/// Why an entry could not be hashed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading the entry failed.
+    Io { path: PathBuf, error: io::Error },
+    /// Listing the directory failed, so some or all of what is under it was
+    /// not hashed.
+    WalkInterrupted { path: PathBuf, error: io::Error },
+    /// The entry is not a regular file, directory, symlink or socket, and
+    /// unknown file types are not ignored.
+    UnsupportedFileType { path: PathBuf },
+    /// The path to hash is not a directory.
+    NotADirectory { path: PathBuf },
+    /// Hashing the directory was cancelled before it was done.
+    Cancelled { path: PathBuf },
+}
+
+impl Options {
+    #[must_use]
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    /// Hash this many entries in parallel, as with `-j`, on a thread pool
+    /// owned by the `Checksummer`. By default entries are hashed on the
+    /// current rayon thread pool, which is whichever one `checksum` is called
+    /// from or else the global one.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.pool = Some(Pool::Threads(threads));
+        self
+    }
+
+    /// Hash entries on the given thread pool, shared with the rest of the
+    /// application, instead of on one of the `Checksummer`'s own. This
+    /// replaces any number of [`threads`][Self::threads].
+    pub fn thread_pool(&mut self, pool: Arc<ThreadPool>) -> &mut Self {
+        self.pool = Some(Pool::Shared(pool));
+        self
+    }
+
+    /// Leave out entries that are not a regular file, directory, symlink or
+    /// socket, rather than failing on them, as with
+    /// `--ignore-unknown-filetypes`.
+    pub fn ignore_unknown_filetypes(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_unknown_filetypes = ignore;
+        self
+    }
+
+    /// Hash with this version of the layout of each entry, such as
+    /// [`FORMAT_V1`], as with `--format-version`, rather than with the latest
+    /// one. Pinning a version guarantees that checksums stored now can be
+    /// compared against ones computed by later releases.
+    pub fn format_version(&mut self, version: u32) -> &mut Self {
+        self.format_version = Some(version);
+        self
+    }
+
+    /// Decide what to do with each entry that is not a regular file,
+    /// directory, symlink or socket, by its path relative to the directory
+    /// being hashed, its metadata and its kind. This takes precedence over
+    /// [`ignore_unknown_filetypes`][Self::ignore_unknown_filetypes]. Calls
+    /// are made one at a time, from whichever thread hashes the entry.
+    pub fn unknown_filetypes<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: FnMut(&Path, &Metadata, EntryKind) -> UnknownAction + Send + 'static,
+    {
+        self.unknown = Some(Arc::new(Mutex::new(handler)));
+        self
+    }
+
+    /// Stop hashing as soon as `cancel` is set, failing with
+    /// [`Error::Cancelled`]. It is checked before each entry and between
+    /// reads of file content, so cancelling takes effect promptly even in the
+    /// middle of a large file. Until it is cleared again, every checksum by
+    /// the `Checksummer` fails the same way.
+    pub fn cancel(&mut self, cancel: Arc<AtomicBool>) -> &mut Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Decide for each entry whether it is hashed, by its path relative to
+    /// the directory being hashed and its metadata. Calls are made one at a
+    /// time, from whichever thread lists the entry's parent directory.
+    pub fn filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: FnMut(&Path, &Metadata) -> Decision + Send + 'static,
+    {
+        self.filter = Some(Arc::new(Mutex::new(filter)));
+        self
+    }
+
+    /// Receive the digest of each entry by its path relative to the directory
+    /// being hashed, such as to record them all instead of writing a
+    /// manifest. The checksum is the XOR of these. Calls are made one at a
+    /// time but in no particular order.
+    pub fn visit<F>(&mut self, visitor: F) -> &mut Self
+    where
+        F: FnMut(&Path, Checksum) + Send + 'static,
+    {
+        self.visitor = Some(Arc::new(Mutex::new(visitor)));
+        self
+    }
+
+    /// Hash each entry's metadata as written by `encoder` instead of as its
+    /// Unix file mode, such as to include attributes that the application
+    /// keeps for its files. The checksums are then no longer those of the
+    /// `sha1dir` command.
+    pub fn encode_metadata<E>(&mut self, encoder: E) -> &mut Self
+    where
+        E: EncodeMetadata + 'static,
+    {
+        self.encoder = Some(Arc::new(encoder));
+        self
+    }
+
+    /// # Errors
+    ///
+    /// Fails if the format version is not one this release knows, or if the
+    /// threads cannot be spawned.
+    pub fn build(&self) -> io::Result<Checksummer> {
+        if let Some(version) = self.format_version {
+            if !(FORMAT_V1..=FORMAT_VERSION).contains(&version) {
+                let message = format!("unsupported format version {}", version);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+        }
+        let pool = match &self.pool {
+            Some(Pool::Threads(threads)) => {
+                let pool = ThreadPoolBuilder::new()
+                    .num_threads(*threads)
+                    .build()
+                    .map_err(io::Error::other)?;
+                Some(Arc::new(pool))
+            }
+            Some(Pool::Shared(pool)) => Some(Arc::clone(pool)),
+            None => None,
+        };
+        Ok(Checksummer {
+            options: self.clone(),
+            pool,
+        })
+    }
+}
+
+impl Debug for Options {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Options")
+            .field("pool", &self.pool)
+            .field("ignore_unknown_filetypes", &self.ignore_unknown_filetypes)
+            .field("format_version", &self.format_version)
+            .field("cancel", &self.cancel)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Checksummer {
+    /// A `Checksummer` with the default options.
+    #[must_use]
+    pub fn new() -> Self {
+        Checksummer::default()
+    }
+
+    /// Hashes the directory at `path`, which may be a symlink to one.
+    ///
+    /// # Errors
+    ///
+    /// Fails with every entry that could not be read, or whose type is not
+    /// supported, once the rest of the tree is hashed.
+    pub fn checksum<P: AsRef<Path>>(&self, path: P) -> Result<Checksum, Errors> {
+        self.checksum_with_progress(path, &NoProgress)
+    }
+
+    /// Hashes the directory at `path` like [`checksum`][Self::checksum],
+    /// telling `progress` about each entry along the way.
+    ///
+    /// # Errors
+    ///
+    /// Fails with every entry that could not be read, or whose type is not
+    /// supported, once the rest of the tree is hashed.
+    pub fn checksum_with_progress<P: AsRef<Path>>(
+        &self,
+        path: P,
+        progress: &dyn Progress,
+    ) -> Result<Checksum, Errors> {
+        self.walk(path.as_ref(), progress, None)
+    }
+
+    /// Hashes the directory at `path` in the background, yielding each entry
+    /// with its digest, and each error, as soon as it is hashed. The checksum
+    /// of the directory is the XOR of the digests of every entry, if there
+    /// are no errors.
+    ///
+    /// Entries come in no particular order. Hashing gets at most a bounded
+    /// number of entries ahead of the iterator, and stops if the iterator is
+    /// dropped.
+    #[must_use]
+    pub fn entries<P: AsRef<Path>>(&self, path: P) -> Entries {
+        let (sender, receiver) = mpsc::sync_channel(STREAM_CAPACITY);
+        let checksummer = self.clone();
+        let root = path.as_ref().to_owned();
+        thread::spawn(move || {
+            let _ = checksummer.walk(&root, &NoProgress, Some(sender));
+        });
+        Entries { receiver }
+    }
+
+    fn walk(
+        &self,
+        root: &Path,
+        progress: &dyn Progress,
+        sender: Option<SyncSender<Result<Entry, Error>>>,
+    ) -> Result<Checksum, Errors> {
+        let walk = Walk {
+            checksummer: self,
+            root,
+            progress,
+            sender,
+            checksum: Mutex::new([0; 20]),
+            errors: Mutex::new(Vec::new()),
+            cancelled: AtomicBool::new(false),
+            abandoned: AtomicBool::new(false),
+        };
+
+        match fs::metadata(root) {
+            Ok(metadata) if metadata.is_dir() => {
+                let walk = || rayon::scope(|scope| walk.children(scope, Path::new("")));
+                match &self.pool {
+                    Some(pool) => pool.install(walk),
+                    None => walk(),
+                }
+            }
+            Ok(_) => walk.fail(Error::NotADirectory {
+                path: root.to_owned(),
+            }),
+            Err(error) => walk.fail(Error::Io {
+                path: root.to_owned(),
+                error,
+            }),
+        }
+
+        if walk.cancelled.load(Ordering::Relaxed) {
+            walk.fail(Error::Cancelled {
+                path: root.to_owned(),
+            });
+        }
+
+        let errors = walk.errors.into_inner();
+        if errors.is_empty() {
+            Ok(Checksum {
+                bytes: walk.checksum.into_inner(),
+            })
+        } else {
+            Err(Errors { errors })
+        }
+    }
+}
+
+/// Receives notice of each entry as it is hashed, such as to show progress.
+///
+/// Entries are hashed in parallel, so these are called from multiple threads
+/// at once and in no particular order. The path of each entry is relative to
+/// the directory being hashed, the same as is hashed into the checksum.
+pub trait Progress: Sync {
+    /// Hashing of the entry has begun.
+    fn started(&self, path: &Path) {
+        let _ = path;
+    }
+
+    /// The entry is hashed, which involved reading `size` bytes of file
+    /// content. Only regular files have content; for anything else `size`
+    /// is zero. The entries under a directory are still to come.
+    fn finished(&self, path: &Path, size: u64) {
+        let _ = (path, size);
+    }
+
+    /// The entry could not be hashed. This is the same error that will be
+    /// among those returned at the end.
+    fn failed(&self, error: &Error) {
+        let _ = error;
+    }
+}
+
+/// Writes the metadata of each entry into its hash, for
+/// [`Options::encode_metadata`].
+///
+/// What is written takes the place of the 4 bytes of the mode in the layout
+/// described by [`FORMAT_V1`]: after the entry's kind and path, and before a
+/// regular file's content or a symlink's target. To add to the mode rather
+/// than replace it, write `metadata.mode().to_le_bytes()` first, by way of
+/// `std::os::unix::fs::MetadataExt`. Calls are made from multiple threads at
+/// once.
+pub trait EncodeMetadata: Send + Sync {
+    /// # Errors
+    ///
+    /// An error is reported as an [`Error::Io`] for the entry, which is left
+    /// out of the checksum.
+    fn encode(&self, path: &Path, metadata: &Metadata, out: &mut dyn Write) -> io::Result<()>;
+}
+
+struct NoProgress;
+
+impl Progress for NoProgress {}
+
+struct Walk<'a> {
+    checksummer: &'a Checksummer,
+    root: &'a Path,
+    progress: &'a dyn Progress,
+    sender: Option<SyncSender<Result<Entry, Error>>>,
+    checksum: Mutex<[u8; 20]>,
+    errors: Mutex<Vec<Error>>,
+    // Whether anything was left out because of cancellation.
+    cancelled: AtomicBool,
+    // Whether the receiver of entries is gone.
+    abandoned: AtomicBool,
+}
+
+impl Walk<'_> {
+    // The path of the root is empty, so that its children's paths have no
+    // leading `./`.
+    fn children<'scope>(&'scope self, scope: &Scope<'scope>, path: &Path) {
+        let read_dir = match fs::read_dir(self.root.join(path)) {
+            Ok(read_dir) => read_dir,
+            Err(error) => return self.fail_walk(path, error),
+        };
+        for child in read_dir {
+            if self.is_cancelled() {
+                return;
+            }
+            let child = match child {
+                Ok(child) => child,
+                Err(error) => return self.fail_walk(path, error),
+            };
+            let path = path.join(child.file_name());
+            let metadata = match child.metadata() {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    self.fail_io(&path, error);
+                    continue;
+                }
+            };
+            if let Some(filter) = &self.checksummer.options.filter {
+                if filter.lock()(&path, &metadata) == Decision::Exclude {
+                    continue;
+                }
+            }
+            scope.spawn(move |scope| self.entry(scope, &path, &metadata));
+        }
+    }
+
+    fn entry<'scope>(&'scope self, scope: &Scope<'scope>, path: &Path, metadata: &Metadata) {
+        let kind = kind(metadata.file_type());
+        let options = &self.checksummer.options;
+        let action = match kind {
+            EntryKind::File | EntryKind::Dir | EntryKind::Symlink | EntryKind::Socket => None,
+            EntryKind::Fifo | EntryKind::Device | EntryKind::Unknown => match &options.unknown {
+                Some(handler) => Some((*handler.lock())(path, metadata, kind)),
+                None if options.ignore_unknown_filetypes => Some(UnknownAction::Skip),
+                None => Some(UnknownAction::Fail),
+            },
+        };
+        if action == Some(UnknownAction::Skip) || self.is_cancelled() {
+            return;
+        }
+
+        self.progress.started(path);
+        let result = if kind == EntryKind::Dir {
+            match self.begin(path, metadata, b'd') {
+                Ok(sha) => {
+                    self.put(path, EntryKind::Dir, 0, sha);
+                    self.progress.finished(path, 0);
+                }
+                Err(error) => self.fail_io(path, error),
+            }
+            return self.children(scope, path);
+        } else if kind == EntryKind::File {
+            self.file(path, metadata)
+        } else if kind == EntryKind::Symlink {
+            self.symlink(path, metadata).map(|()| Some(0))
+        } else if action == Some(UnknownAction::Fail) {
+            let path = self.root.join(path);
+            return self.fail(Error::UnsupportedFileType { path });
+        } else {
+            self.begin(path, metadata, b's').map(|sha| {
+                self.put(path, kind, 0, sha);
+                Some(0)
+            })
+        };
+        match result {
+            Ok(Some(size)) => self.progress.finished(path, size),
+            Ok(None) => {}
+            Err(error) => self.fail_io(path, error),
+        }
+    }
+
+    // None if cancelled partway through.
+    fn file(&self, path: &Path, metadata: &Metadata) -> io::Result<Option<u64>> {
+        let mut sha = self.begin(path, metadata, b'f')?;
+        let mut file = File::open(self.root.join(path))?;
+        let mut buf = vec![0; BUFFER_LEN];
+        let mut size = 0;
+        loop {
+            if self.is_cancelled() {
+                return Ok(None);
+            }
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    sha.update(&buf[..n]);
+                    size += n as u64;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+        self.put(path, EntryKind::File, size, sha);
+        Ok(Some(size))
+    }
+
+    fn symlink(&self, path: &Path, metadata: &Metadata) -> io::Result<()> {
+        let mut sha = self.begin(path, metadata, b'l')?;
+        let target = fs::read_link(self.root.join(path))?;
+        sha.update(target.as_os_str().as_encoded_bytes());
+        self.put(path, EntryKind::Symlink, 0, sha);
+        Ok(())
+    }
+
+    fn begin(&self, path: &Path, metadata: &Metadata, kind: u8) -> io::Result<Sha1> {
+        let mut sha = begin(path, kind);
+        match &self.checksummer.options.encoder {
+            Some(encoder) => encoder.encode(path, metadata, &mut sha)?,
+            None => sha.update(mode(metadata).to_le_bytes()),
+        }
+        Ok(sha)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        if self.abandoned.load(Ordering::Relaxed) {
+            return true;
+        }
+        let Some(cancel) = &self.checksummer.options.cancel else {
+            return false;
+        };
+        let cancelled = cancel.load(Ordering::Relaxed);
+        if cancelled {
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+        cancelled
+    }
+
+    fn put(&self, path: &Path, kind: EntryKind, size: u64, sha: Sha1) {
+        let digest: [u8; 20] = sha.finalize().into();
+        if let Some(visitor) = &self.checksummer.options.visitor {
+            visitor.lock()(path, Checksum { bytes: digest });
+        }
+        if let Some(sender) = &self.sender {
+            let entry = Entry {
+                path: path.to_owned(),
+                kind,
+                digest: Checksum { bytes: digest },
+                size,
+            };
+            self.send(sender, Ok(entry));
+        }
+        for (lhs, rhs) in self.checksum.lock().iter_mut().zip(digest) {
+            *lhs ^= rhs;
+        }
+    }
+
+    fn fail_io(&self, path: &Path, error: io::Error) {
+        let path = self.root.join(path);
+        self.fail(Error::Io { path, error });
+    }
+
+    fn fail_walk(&self, path: &Path, error: io::Error) {
+        let path = if path.as_os_str().is_empty() {
+            self.root.to_owned()
+        } else {
+            self.root.join(path)
+        };
+        self.fail(Error::WalkInterrupted { path, error });
+    }
+
+    fn fail(&self, error: Error) {
+        self.progress.failed(&error);
+        if let Some(sender) = &self.sender {
+            return self.send(sender, Err(error));
+        }
+        self.errors.lock().push(error);
+    }
+
+    fn send(&self, sender: &SyncSender<Result<Entry, Error>>, result: Result<Entry, Error>) {
+        if sender.send(result).is_err() {
+            self.abandoned.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    metadata.mode()
+}
+
+// Without a mode, as on WASI, the file type is combined with the usual
+// permissions, so that the checksum matches that of the same tree on unix
+// only if it has those permissions.
+#[cfg(not(unix))]
+#[allow(clippy::unreadable_literal)]
+fn mode(metadata: &Metadata) -> u32 {
+    let file_type = metadata.file_type();
+    let (kind, permissions) = if file_type.is_dir() {
+        (0o040000, 0o755)
+    } else if file_type.is_symlink() {
+        (0o120000, 0o777)
+    } else {
+        (0o100000, 0o644)
+    };
+    if metadata.permissions().readonly() {
+        kind | permissions & 0o555
+    } else {
+        kind | permissions
+    }
+}
+
+fn kind(file_type: FileType) -> EntryKind {
+    if file_type.is_file() {
+        EntryKind::File
+    } else if file_type.is_dir() {
+        EntryKind::Dir
+    } else if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else {
+        special_kind(file_type)
+    }
+}
+
+#[cfg(unix)]
+fn special_kind(file_type: FileType) -> EntryKind {
+    if file_type.is_socket() {
+        EntryKind::Socket
+    } else if file_type.is_fifo() {
+        EntryKind::Fifo
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        EntryKind::Device
+    } else {
+        EntryKind::Unknown
+    }
+}
+
+// Nothing else is distinguished.
+#[cfg(not(unix))]
+fn special_kind(_file_type: FileType) -> EntryKind {
+    EntryKind::Unknown
+}
+
+// Followed by the metadata.
+fn begin(path: &Path, kind: u8) -> Sha1 {
+    let mut sha = Sha1::new();
+    let path_bytes = path.as_os_str().as_encoded_bytes();
+    sha.update([kind]);
+    sha.update((path_bytes.len() as u32).to_le_bytes());
+    sha.update(path_bytes);
+    sha
+}
+
+impl Checksum {
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.bytes
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; 20] {
+        self.bytes
+    }
+
+    /// Compares in time that does not depend on where the checksums first
+    /// differ, for checking a digest that an attacker may have chosen.
+    #[must_use]
+    pub fn ct_eq(&self, other: &Checksum) -> bool {
+        let mut difference = 0;
+        for (lhs, rhs) in self.bytes.iter().zip(&other.bytes) {
+            difference |= lhs ^ rhs;
+        }
+        hint::black_box(difference) == 0
+    }
+}
+
+impl From<[u8; 20]> for Checksum {
+    fn from(bytes: [u8; 20]) -> Self {
+        Checksum { bytes }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = ParseChecksumError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        let error = ParseChecksumError { _private: () };
+        if hex.len() != 40 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(error);
+        }
+        let mut bytes = [0u8; 20];
+        for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = str::from_utf8(pair).map_err(|_| error)?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| error)?;
+        }
+        Ok(Checksum { bytes })
+    }
+}
+
+impl TryFrom<&str> for Checksum {
+    type Error = ParseChecksumError;
+
+    fn try_from(hex: &str) -> Result<Self, Self::Error> {
+        hex.parse()
+    }
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.bytes {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Checksum({})", self)
+    }
+}
+
+impl Display for ParseChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("expected 40 hex digits")
+    }
+}
+
+impl StdError for ParseChecksumError {}
+
+impl Entry {
+    /// The path relative to the directory being hashed.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    #[must_use]
+    pub fn digest(&self) -> Checksum {
+        self.digest
+    }
+
+    /// Bytes of content hashed, which is zero for anything other than a
+    /// regular file.
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Iterator for Entries {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Errors {
+    /// Each entry that could not be hashed, with why.
+    pub fn iter(&self) -> slice::Iter<'_, Error> {
+        self.errors.iter()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl IntoIterator for Errors {
+    type Item = Error;
+    type IntoIter = vec::IntoIter<Error>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Errors {
+    type Item = &'a Error;
+    type IntoIter = slice::Iter<'a, Error>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.iter()
+    }
+}
+
+impl Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.errors[0], f)?;
+        if self.errors.len() > 1 {
+            write!(f, " (and {} more errors)", self.errors.len() - 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl StdError for Errors {}
+
+impl Error {
+    /// The entry that could not be hashed.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        match self {
+            Error::Io { path, .. }
+            | Error::WalkInterrupted { path, .. }
+            | Error::UnsupportedFileType { path }
+            | Error::NotADirectory { path }
+            | Error::Cancelled { path } => path,
+        }
+    }
+
+    /// The errno of a failed system call, if that is what failed.
+    #[must_use]
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Error::Io { error, .. } | Error::WalkInterrupted { error, .. } => error.raw_os_error(),
+            Error::UnsupportedFileType { .. }
+            | Error::NotADirectory { .. }
+            | Error::Cancelled { .. } => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, error } | Error::WalkInterrupted { path, error } => {
+                write!(f, "{}: {}", path.display(), error)
+            }
+            Error::UnsupportedFileType { path } => {
+                write!(f, "{}: Unsupported file type", path.display())
+            }
+            Error::NotADirectory { path } => write!(f, "{}: Not a directory", path.display()),
+            Error::Cancelled { path } => write!(f, "{}: Cancelled", path.display()),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io { error, .. } | Error::WalkInterrupted { error, .. } => Some(error),
+            Error::UnsupportedFileType { .. }
+            | Error::NotADirectory { .. }
+            | Error::Cancelled { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! Paths of the entries that would be hashed, for --list.
+//!
+//! Whatever decides which entries are part of the checksum, such as --shard
+//! and --ignore-unknown-filetypes, applies the same as when hashing. The
+//! paths of each DIR are sorted in the same order as in a manifest.
+
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub fn write(
+    out: &mut dyn Write,
+    paths: &[PathBuf],
+    label: Option<&Path>,
+    eol: u8,
+) -> io::Result<()> {
+    for path in paths {
+        match label {
+            Some(label) => out.write_all(label.join(path).as_os_str().as_bytes())?,
+            None => out.write_all(path.as_os_str().as_bytes())?,
+        }
+        out.write_all(&[eol])?;
+    }
+    Ok(())
+}
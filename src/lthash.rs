@@ -0,0 +1,92 @@
+//! Combining of entry digests by addition rather than XOR, for --combine
+//! lthash.
+//!
+//! This is the lattice hash with the parameters of Facebook's `LtHash16`:
+//! each digest is expanded to 1024 lanes of 16 bits by SHA-256 in counter
+//! mode, and the lanes are added into the state with wrapping. The result is
+//! as independent of order as XOR, but whereas a set of entries with any
+//! chosen XOR can be found by solving linear equations over enough candidate
+//! entries, finding one with a chosen sum is a lattice problem that is
+//! believed to be hard. The state is reduced to a checksum by its SHA-1, in
+//! little endian order, so that it is as wide as any other.
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+pub const LANES: usize = 1024;
+
+pub struct LtHash {
+    lanes: Box<[u16; LANES]>,
+}
+
+impl LtHash {
+    pub fn new() -> Self {
+        LtHash {
+            lanes: Box::new([0; LANES]),
+        }
+    }
+
+    pub fn add(&mut self, expanded: &[u16; LANES]) {
+        for (lane, rhs) in self.lanes.iter_mut().zip(expanded.iter()) {
+            *lane = lane.wrapping_add(*rhs);
+        }
+    }
+
+    pub fn digest(&self) -> [u8; 20] {
+        let mut sha = Sha1::new();
+        for lane in self.lanes.iter() {
+            sha.update(lane.to_le_bytes());
+        }
+        sha.finalize().into()
+    }
+}
+
+// Computed before taking the lock on the state, which leaves only the
+// additions under it.
+pub fn expand(digest: &[u8; 20]) -> Box<[u16; LANES]> {
+    let mut lanes = Box::new([0; LANES]);
+    for (counter, block) in lanes.chunks_mut(16).enumerate() {
+        let mut sha = Sha256::new();
+        sha.update(digest);
+        sha.update((counter as u32).to_le_bytes());
+        let bytes = sha.finalize();
+        for (lane, pair) in block.iter_mut().zip(bytes.chunks(2)) {
+            *lane = u16::from_le_bytes([pair[0], pair[1]]);
+        }
+    }
+    lanes
+}
+
+#[test]
+fn test_lthash() {
+    let digests: Vec<[u8; 20]> = (0..4u8).map(|i| Sha1::digest([i]).into()).collect();
+    let combine = |digests: &[[u8; 20]]| {
+        let mut lthash = LtHash::new();
+        for digest in digests {
+            lthash.add(&expand(digest));
+        }
+        lthash.digest()
+    };
+
+    let forward = combine(&digests);
+    let mut reversed = digests.clone();
+    reversed.reverse();
+    assert_eq!(combine(&reversed), forward);
+    assert_ne!(combine(&digests[..3]), forward);
+
+    // Adding the negation of an entry's lanes takes it back out.
+    let mut lthash = LtHash::new();
+    for digest in &digests {
+        lthash.add(&expand(digest));
+    }
+    let mut negated = expand(&digests[3]);
+    for lane in negated.iter_mut() {
+        *lane = lane.wrapping_neg();
+    }
+    lthash.add(&negated);
+    assert_eq!(lthash.digest(), combine(&digests[..3]));
+
+    // Unlike XOR, adding an entry twice does not cancel it out.
+    let twice = [digests[0], digests[0]];
+    assert_ne!(combine(&twice), combine(&[]));
+}
@@ -6,33 +6,108 @@
 
 #![allow(
     clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
     clippy::let_underscore_untyped,
     clippy::needless_collect,
     clippy::needless_pass_by_value,
+    clippy::struct_excessive_bools,
+    clippy::too_many_lines,
     clippy::uninlined_format_args,
     clippy::unnecessary_wraps,
     clippy::unseparated_literal_suffix
 )]
 
-use clap::Parser;
-use memmap::Mmap;
+mod advice;
+mod archive;
+mod attestation;
+mod audit;
+mod bench;
+mod budget;
+mod capabilities;
+mod cas;
+mod cdc;
+mod check;
+mod compat;
+mod cpio;
+mod diff;
+mod dirfd;
+mod estimate;
+mod etag;
+mod git;
+mod gpg;
+mod list;
+mod lthash;
+mod manifest;
+mod manpage;
+mod merkle;
+mod nar;
+mod numa;
+mod order;
+mod priority;
+mod read;
+mod readdir;
+mod remote;
+mod rlimit;
+mod sign;
+mod spdx;
+mod stat;
+mod state;
+mod storage;
+mod throttle;
+mod tune;
+mod walk;
+mod xattr;
+
+use crate::audit::AuditLog;
+use crate::budget::MapBudget;
+use crate::cas::Cas;
+use crate::cdc::{ChunkLists, Chunker};
+use crate::diff::Style;
+use crate::dirfd::{At, Dir};
+use crate::estimate::Totals;
+use crate::etag::Etags;
+use crate::lthash::LtHash;
+use crate::manifest::{Entry, Manifest};
+use crate::order::Order;
+use crate::priority::IoPriority;
+use crate::spdx::{Checksums, SpdxFiles};
+use crate::stat::Stat;
+use crate::state::State;
+use crate::throttle::Throttle;
+use crate::walk::Traversal;
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+#[cfg(feature = "mmap")]
+use memmap::{Mmap, MmapOptions};
 use parking_lot::Mutex;
-use rayon::{Scope, ThreadPoolBuilder};
+use rayon::iter::ParallelIterator;
+use rayon::slice::ParallelSlice;
+use rayon::ThreadPoolBuilder;
 use sha1::{Digest, Sha1};
+use std::borrow::Cow;
 use std::cmp;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::fmt::{self, Display};
-use std::fs::{self, File, Metadata};
-use std::io::{self, Write};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Write};
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::fs::{FileTypeExt, MetadataExt};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process;
+use std::slice;
+use std::str;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Once;
+use std::time::SystemTime;
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
+// Files are mapped this much at a time, so that a file larger than the
+// address space of a 32-bit target can still be hashed.
+const MAP_WINDOW: u64 = 256 << 20;
+
 fn die<P: AsRef<Path>, E: Display>(path: P, error: E) -> ! {
     static DIE: Once = Once::new();
 
@@ -54,54 +129,1196 @@ fn die<P: AsRef<Path>, E: Display>(path: P, error: E) -> ! {
 #[derive(Debug, Parser)]
 #[command(about = "Compute checksum of directory.", version, author)]
 struct Opt {
-    /// Number of hashes to compute in parallel
-    #[arg(short)]
-    jobs: Option<usize>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Number of hashes to compute in parallel on each device, or `auto` to
+    /// adjust it according to the observed throughput; by default chosen by
+    /// whether the device is a spinning disk, solid state or over the network
+    #[arg(global = true, short, value_name = "JOBS", value_parser = parse_jobs)]
+    jobs: Option<Jobs>,
+
+    /// Number of directories to list in parallel, including reading the
+    /// metadata of each entry; defaults to the number of hashing jobs, or of
+    /// CPUs with `-j auto`
+    #[arg(global = true, long, value_name = "N")]
+    walk_jobs: Option<usize>,
+
+    /// Limit reading of file content to RATE bytes per second in total
+    /// across all threads, with an optional K, M or G suffix
+    #[arg(global = true, long, value_name = "RATE", value_parser = parse_nonzero_size)]
+    bwlimit: Option<u64>,
+
+    /// Run with CPU scheduling niceness N, as by `nice -n N`
+    #[arg(global = true, long, value_name = "N", allow_negative_numbers = true)]
+    nice: Option<i32>,
+
+    /// Run in I/O scheduling class CLASS (realtime, best-effort or idle) with
+    /// optional priority LEVEL from 0 to 7, as by `ionice -c CLASS -n LEVEL`
+    #[arg(global = true, long, value_name = "CLASS[:LEVEL]", value_parser = priority::parse_ionice)]
+    ionice: Option<IoPriority>,
+
+    /// Run only on the CPUs of NUMA node N and prefer its memory, such as
+    /// the node closest to the storage controller or network card
+    #[arg(global = true, long, value_name = "N")]
+    numa_node: Option<usize>,
+
+    /// Open files with `O_NOATIME` where permitted, so that hashing does not
+    /// update their access time
+    #[arg(global = true, long)]
+    noatime: bool,
+
+    /// Evict each file's content from the page cache after hashing it, to
+    /// avoid pushing out the cache of other processes
+    #[arg(global = true, long)]
+    drop_cache: bool,
+
+    /// Start reading the beginning of each file in a directory as soon as the
+    /// directory is listed, rather than when a thread gets to the file
+    #[arg(global = true, long)]
+    readahead: bool,
+
+    /// Read files with `O_DIRECT` into a buffer instead of mapping them,
+    /// bypassing the page cache
+    #[arg(global = true, long, conflicts_with_all = ["drop_cache", "readahead", "tar", "cpio", "no_mmap", "mmap_threshold"])]
+    direct_io: bool,
+
+    /// Read files into a buffer instead of mapping them, for filesystems that
+    /// do not support mmap; an archive is read into memory in full
+    #[arg(global = true, long, conflicts_with = "mmap_threshold")]
+    no_mmap: bool,
+
+    /// Read files smaller than BYTES into a buffer and map only larger ones,
+    /// with an optional K, M or G suffix
+    #[arg(global = true, long, value_name = "BYTES", value_parser = parse_size)]
+    mmap_threshold: Option<u64>,
+
+    /// Wait before mapping a file while files totalling more than BYTES are
+    /// mapped by other threads, with an optional K, M or G suffix
+    #[arg(global = true, long, value_name = "BYTES", value_parser = parse_nonzero_size, conflicts_with_all = ["no_mmap", "direct_io"])]
+    max_map_memory: Option<u64>,
+
+    /// Hash regular files larger than SIZE as consecutive SIZE-byte chunks on
+    /// multiple threads, with an optional K, M or G suffix; this changes the
+    /// checksum of such files, as described in the README
+    #[arg(global = true, long, value_name = "SIZE", value_parser = parse_nonzero_size, conflicts_with_all = ["etag_compat", "manifest_format", "state", "resume", "direct_io", "no_mmap"])]
+    chunked: Option<u64>,
+
+    /// Hash the files on each device in this order, after listing the whole
+    /// tree
+    #[arg(global = true, long, value_enum, conflicts_with_all = ["tar", "cpio"])]
+    order: Option<Order>,
+
+    /// How to go through the tree
+    #[arg(global = true, long, value_enum, default_value_t = Traversal::Dfs, conflicts_with_all = ["tar", "cpio"])]
+    traversal: Traversal,
 
     /// Directories to hash
     #[arg(value_name = "DIR")]
     dirs: Vec<PathBuf>,
 
+    /// Hash each DIR argument as a tar archive, producing the checksum of the
+    /// directory it would extract into
+    #[arg(global = true, long, conflicts_with_all = ["state", "resume"])]
+    tar: bool,
+
+    /// Hash each DIR argument as a cpio archive in the "newc" format used by
+    /// initramfs, producing the checksum of the directory it would extract into
+    #[arg(global = true, long, conflicts_with_all = ["tar", "state", "resume"])]
+    cpio: bool,
+
+    /// Print the number of entries and bytes that hashing each DIR would
+    /// read, and how long that would take, without reading any content
+    #[arg(global = true, long, conflicts_with_all = ["tar", "cpio", "readahead", "chunked", "state", "resume", "audit_log", "expect", "manifest", "manifest_format", "etag_compat", "attestation", "sign", "gpg_sign", "check", "format", "compat"])]
+    estimate: bool,
+
+    /// Throughput in bytes per second to project the duration printed by
+    /// --estimate from, with an optional K, M or G suffix
+    #[arg(global = true, long, value_name = "RATE", value_parser = parse_nonzero_size, default_value = "100M", requires = "estimate")]
+    estimate_rate: u64,
+
+    /// Print the path of every entry in each DIR that would be hashed,
+    /// without reading any content
+    #[arg(global = true, long, conflicts_with_all = ["estimate", "tar", "cpio", "readahead", "chunked", "state", "resume", "audit_log", "expect", "manifest", "manifest_format", "etag_compat", "attestation", "sign", "gpg_sign", "check", "format", "compat"])]
+    list: bool,
+
+    /// Also print the number of entries in each DIR that the checksum covers
+    /// and the total size of its regular files, and the same for each
+    /// checksum of --per-entry or --rollup-depth
+    #[arg(global = true, long, conflicts_with_all = ["manifest", "manifest_format", "expect", "estimate", "list", "check", "compat"])]
+    totals: bool,
+
     /// Whether to ignore unknown filetypes (otherwise fatal)
-    #[arg(long)]
+    #[arg(global = true, long)]
     ignore_unknown_filetypes: bool,
+
+    /// Leave doors and event ports out of the checksum, on illumos, instead of
+    /// hashing them like sockets
+    #[arg(global = true, long)]
+    skip_doors: bool,
+
+    /// Include the extended attributes of every entry, such as the resource
+    /// fork and Finder info on macOS, in its hash
+    #[arg(global = true, long, conflicts_with_all = ["tar", "cpio", "state", "resume", "compat"])]
+    xattrs: bool,
+
+    /// Include the BSD file flags of every entry, such as uchg and hidden, in
+    /// its hash
+    #[arg(global = true, long, conflicts_with_all = ["tar", "cpio", "state", "resume", "compat"])]
+    file_flags: bool,
+
+    /// Print the limits that the run is subject to, such as the number of
+    /// open files, to stderr
+    #[arg(global = true, short, long)]
+    verbose: bool,
+
+    /// Record progress to FILE so that an interrupted run can be resumed
+    #[arg(global = true, long, value_name = "FILE", conflicts_with = "resume")]
+    state: Option<PathBuf>,
+
+    /// Resume from a state FILE written by --state, and keep recording to it
+    #[arg(global = true, long, value_name = "FILE")]
+    resume: Option<PathBuf>,
+
+    /// Append a record to FILE of every file opened, with the time, bytes
+    /// read and result, each chained to the previous one by hash
+    #[arg(global = true, long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+
+    /// Print OK or FAILED instead of the checksum, by comparing against
+    /// DIGEST, and exit with status 1 on any mismatch; give once per DIR
+    #[arg(global = true, long, value_name = "DIGEST", value_parser = parse_digest, conflicts_with_all = ["manifest", "manifest_format", "etag_compat", "check"])]
+    expect: Vec<[u8; 20]>,
+
+    /// Hash only the entries assigned to shard K of N by a hash of their
+    /// path; the XOR of the checksums of all N shards is the full checksum
+    #[arg(global = true, long, value_name = "K/N", value_parser = parse_shard, conflicts_with = "check")]
+    shard: Option<Shard>,
+
+    /// Also print the digest of every entry in each directory
+    #[arg(global = true, long)]
+    manifest: bool,
+
+    /// Format of the listing printed instead of the checksum; implies
+    /// --manifest
+    #[arg(
+        global = true,
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        conflicts_with = "etag_compat"
+    )]
+    manifest_format: Option<ManifestFormat>,
+
+    /// Also print the S3 etag that a multipart upload with the given part
+    /// size would produce for each regular file
+    #[arg(global = true, long, value_name = "CHUNKSIZE", value_parser = parse_nonzero_size, conflicts_with = "manifest")]
+    etag_compat: Option<u64>,
+
+    /// Write an in-toto statement to FILE with the checksum of each directory
+    /// and the digest of every entry as subjects
+    #[arg(global = true, long, value_name = "FILE")]
+    attestation: Option<PathBuf>,
+
+    /// Also print the checksum of each entry directly inside each DIR together
+    /// with everything under it
+    #[arg(global = true, long, conflicts_with_all = ["manifest", "manifest_format", "estimate", "list", "check", "compat"])]
+    per_entry: bool,
+
+    /// Also print the checksum of each directory down to depth N inside each
+    /// DIR together with everything under it
+    #[arg(global = true, long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["per_entry", "manifest", "manifest_format", "estimate", "list", "check", "compat"])]
+    rollup_depth: Option<u32>,
+
+    /// Write the digest of every directory, computed from its children's
+    /// digests, to FILE as a Merkle tree
+    #[arg(global = true, long, value_name = "FILE", conflicts_with_all = ["estimate", "list", "compat"])]
+    merkle: Option<PathBuf>,
+
+    /// Write the digest, offset and length of each content-defined chunk of
+    /// every regular file to FILE
+    #[arg(global = true, long, value_name = "FILE", conflicts_with_all = ["chunked", "estimate", "list", "check", "compat"])]
+    chunked_manifest: Option<PathBuf>,
+
+    /// End each line of output with a NUL byte instead of a newline, and read
+    /// manifests the same way, so that paths may contain newlines
+    #[arg(global = true, short, long)]
+    zero: bool,
+
+    /// Write the output to FILE, which is replaced only once it is complete,
+    /// instead of to stdout
+    #[arg(global = true, long, value_name = "FILE", conflicts_with_all = ["estimate", "list", "check", "format", "compat"])]
+    output: Option<PathBuf>,
+
+    /// Append a minisign signature of the output, made with the secret key in
+    /// KEYFILE
+    #[arg(global = true, long, value_name = "KEYFILE")]
+    sign: Option<PathBuf>,
+
+    /// Append an ASCII-armored GPG signature of the output, made by gpg
+    /// with the key KEYID
+    #[arg(global = true, long, value_name = "KEYID", conflicts_with = "sign")]
+    gpg_sign: Option<String>,
+
+    /// Check the directories listed in a manifest FILE written by --manifest
+    /// against their current content
+    #[arg(global = true, long, value_name = "FILE", conflicts_with_all = ["tar", "cpio", "state", "resume", "manifest", "manifest_format", "etag_compat", "attestation", "sign", "gpg_sign", "compat", "format"])]
+    check: Option<PathBuf>,
+
+    /// Refuse to check a manifest unless it carries a valid signature from
+    /// the minisign public key in PUBKEY
+    #[arg(global = true, long, value_name = "PUBKEY")]
+    verify_signature: Option<PathBuf>,
+
+    /// Check only a deterministic sample of PERCENT% of the entries listed in
+    /// the manifest, and estimate how many differ in total
+    #[arg(global = true, long, value_name = "PERCENT", value_parser = parse_percent)]
+    verify_sample: Option<f64>,
+
+    /// Choose a different sample for --verify-sample
+    #[arg(
+        global = true,
+        long,
+        value_name = "SEED",
+        default_value_t = 0,
+        requires = "verify_sample"
+    )]
+    sample_seed: u64,
+
+    /// Refuse to check a manifest unless gpg accepts its signature
+    #[arg(global = true, long, conflicts_with = "verify_signature")]
+    gpg_verify: bool,
+
+    /// Kind of checksum to compute
+    #[arg(global = true, long, value_enum, default_value_t = Format::Sha1dir)]
+    format: Format,
+
+    /// Version of the layout of each entry that is hashed, as described in
+    /// the README; every earlier version stays selectable, so that stored
+    /// checksums can still be compared
+    #[arg(
+        global = true,
+        long,
+        value_name = "N",
+        default_value_t = sha1dir::FORMAT_VERSION,
+        value_parser = clap::value_parser!(u32).range(i64::from(sha1dir::FORMAT_V1)..=i64::from(sha1dir::FORMAT_VERSION)),
+    )]
+    format_version: u32,
+
+    /// How to combine the digests of the entries into the checksum
+    #[arg(global = true, long, value_enum, default_value_t = Combine::Xor, conflicts_with = "compat")]
+    combine: Combine,
+
+    /// Compute a checksum compatible with a shell pipeline instead
+    #[arg(
+        global = true,
+        long,
+        value_enum,
+        conflicts_with_all = ["format", "manifest", "manifest_format", "etag_compat", "attestation", "sign", "gpg_sign", "tar", "cpio", "state", "resume", "audit_log", "expect", "shard", "bwlimit", "chunked"],
+    )]
+    compat: Option<Compat>,
+
+    /// Whether to color the entries listed by diff, verify and the other
+    /// comparisons according to how they differ
+    #[arg(global = true, long, value_name = "WHEN", value_enum, default_value_t = Color::Auto)]
+    color: Color,
+
+    /// Print a JSON document of the formats, algorithms and platform
+    /// features that this build supports, then exit
+    #[arg(global = true, long)]
+    capabilities: bool,
+}
+
+impl Opt {
+    fn eol(&self) -> u8 {
+        if self.zero {
+            b'\0'
+        } else {
+            b'\n'
+        }
+    }
+
+    // NO_COLOR is honored as described at https://no-color.org.
+    fn style(&self) -> Style {
+        let color = match self.color {
+            Color::Always => true,
+            Color::Never => false,
+            Color::Auto => io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        };
+        Style {
+            eol: self.eol(),
+            color,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Color {
+    /// Color the output only if it is to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Format {
+    /// XOR of the SHA-1 of every entry, computed in parallel
+    Sha1dir,
+    /// SHA-256 of the Nix archive serialization, as used by Nix binary caches
+    Nar,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Shard {
+    /// Zero-based, unlike on the command line.
+    index: u64,
+    count: u64,
+}
+
+impl Shard {
+    // Dependent only on the path relative to DIR, so every host agrees on
+    // the assignment regardless of where the tree is mounted.
+    fn contains(self, path: &Path) -> bool {
+        let hash = Sha1::digest(path.as_os_str().as_bytes());
+        let hash = u64::from_be_bytes(hash[..8].try_into().unwrap());
+        hash % self.count == self.index
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum ManifestFormat {
+    /// Digest of every entry, as printed by --manifest
+    Sha1dir,
+    /// SPDX tag-value file sections with the SHA-1 and SHA-256 of every
+    /// regular file
+    Spdx,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum Combine {
+    /// Bitwise XOR of the digests
+    Xor,
+    /// Sum of the digests, each expanded to 1024 16-bit lanes, which unlike
+    /// XOR resists finding entries that produce a chosen checksum
+    Lthash,
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum Compat {
+    /// `find . -type f -print0 | LC_ALL=C sort -z | xargs -0 cat | sha1sum`
+    CatPipeline,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Measure the rate of hashing, of listing DIR and of hashing DIR with
+    /// different numbers of jobs
+    Bench(Bench),
+
+    /// Compare two directories or tar archives entry by entry
+    Diff(Diff),
+
+    /// Hash DIR while adding the content of its files to a content-addressed
+    /// store in OUT, along with a listing of its tree
+    ExportCas(ExportCas),
+
+    /// Compare a git working tree against the files committed in HEAD
+    GitVerify(GitVerify),
+
+    /// Compute the checksum of each DIR, the same as with no subcommand
+    Hash(Hash),
+
+    /// Print a manual page in roff format, for `man -l`
+    Manpage,
+
+    /// Compare a directory on another host, over ssh, against a local one
+    RemoteDiff(RemoteDiff),
+
+    /// Hash the directories listed in a manifest written by --manifest again,
+    /// print how they differ, and rewrite the manifest with their current
+    /// content
+    Update(Update),
+
+    /// Check the directories listed in a manifest written by --manifest
+    /// against their current content, the same as --check
+    Verify(Verify),
+}
+
+#[derive(Debug, Args)]
+struct Hash {
+    /// Directories to hash
+    #[arg(value_name = "DIR")]
+    dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct Update {
+    /// Manifest to rewrite
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct Verify {
+    /// Manifest to check against
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct Bench {
+    /// Directory to list and hash
+    #[arg(value_name = "DIR", default_value = ".")]
+    dir: PathBuf,
+
+    /// Numbers of jobs to try, separated by commas
+    #[arg(id = "bench_jobs", long = "jobs", value_name = "N,...", value_delimiter = ',', value_parser = parse_nonzero_count, default_values_t = [1, 2, 4, 8, 16])]
+    jobs: Vec<usize>,
+}
+
+#[derive(Debug, Args)]
+struct GitVerify {
+    /// Any directory inside the repository
+    #[arg(value_name = "REPO", default_value = ".")]
+    repo: PathBuf,
+}
+
+#[derive(Debug, Args)]
+struct Diff {
+    /// Original directory or tar archive
+    #[arg(value_name = "EXPECTED")]
+    expected: PathBuf,
+
+    /// Directory or tar archive expected to match the original
+    #[arg(value_name = "ACTUAL")]
+    actual: PathBuf,
+
+    #[command(flatten)]
+    report: Report,
+}
+
+#[derive(Debug, Args)]
+struct ExportCas {
+    /// Directory to hash and export
+    #[arg(value_name = "DIR")]
+    dir: PathBuf,
+
+    /// Content-addressed store, created if it does not exist
+    #[arg(value_name = "OUT")]
+    out: PathBuf,
+
+    /// Copy each file into the store instead of hard linking it
+    #[arg(long)]
+    copy: bool,
+}
+
+#[derive(Debug, Args)]
+struct RemoteDiff {
+    /// Directory on the remote host
+    #[arg(value_name = "[USER@]HOST:PATH")]
+    remote: String,
+
+    /// Local directory expected to match the remote one
+    #[arg(value_name = "DIR")]
+    local: PathBuf,
+
+    /// Path of the sha1dir executable on the remote host
+    #[arg(long, value_name = "PROGRAM", default_value = "sha1dir")]
+    sha1dir_path: String,
+
+    #[command(flatten)]
+    report: Report,
+}
+
+#[derive(Debug, Args)]
+struct Report {
+    /// Write the paths of changed and missing entries to FILE, one per line,
+    /// for use with `rsync --files-from`
+    #[arg(long, value_name = "FILE")]
+    repair_list: Option<PathBuf>,
+
+    /// Write rsync filter rules to FILE that include only the entries which
+    /// differ, for `rsync -a --delete --filter='merge FILE' EXPECTED/ ACTUAL/`
+    #[arg(long, value_name = "FILE")]
+    emit_rsync_filter: Option<PathBuf>,
 }
 
 fn main() {
-    let opt = Opt::parse();
+    let mut opt = Opt::parse();
+    if opt.capabilities {
+        capabilities::run();
+    }
+    // The subcommands `hash` and `verify` are the same as giving DIR or
+    // --check without a subcommand.
+    match &mut opt.command {
+        Some(Command::Hash(args)) => {
+            opt.dirs = mem::take(&mut args.dirs);
+            opt.command = None;
+        }
+        Some(Command::Verify(args)) => {
+            opt.check = Some(mem::take(&mut args.file));
+            opt.command = None;
+        }
+        _ => {}
+    }
+    if opt.check.is_none()
+        && (opt.verify_signature.is_some() || opt.verify_sample.is_some() || opt.gpg_verify)
+    {
+        let message =
+            "--verify-signature, --verify-sample and --gpg-verify require `verify` or --check";
+        Opt::command()
+            .error(ErrorKind::MissingRequiredArgument, message)
+            .exit();
+    }
+    if (opt.tar || opt.cpio) && opt.dirs.is_empty() && opt.command.is_none() {
+        let message = "--tar and --cpio require the archives to be given as DIR";
+        Opt::command()
+            .error(ErrorKind::MissingRequiredArgument, message)
+            .exit();
+    }
+    if opt.check.is_some() && !opt.dirs.is_empty() {
+        let message = "--check cannot be combined with DIR";
+        Opt::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+
+    // The default soft limit of 1024 is often far below the hard limit, and
+    // too few for many threads on a deep tree.
+    rlimit::raise_nofile();
+    if opt.verbose {
+        let nofile = rlimit::nofile().map_or_else(|| "unlimited".to_owned(), |n| n.to_string());
+        let _ = writeln!(
+            io::stderr(),
+            "{}: open file limit: {}",
+            env!("CARGO_BIN_NAME"),
+            nofile,
+        );
+    }
+
+    // Inherited by the thread pool's threads only if set first.
+    if let Some(nice) = opt.nice {
+        if let Err(error) = priority::set_nice(nice) {
+            die("--nice", error);
+        }
+    }
+    if let Some(ionice) = opt.ionice {
+        if let Err(error) = priority::set_ionice(ionice) {
+            die("--ionice", error);
+        }
+    }
+    if let Some(node) = opt.numa_node {
+        if let Err(error) = numa::bind(node) {
+            die("--numa-node", error);
+        }
+    }
+
+    if opt.xattrs && !xattr::SUPPORTED {
+        die("--xattrs", "not supported on this platform");
+    }
+    if opt.file_flags && !stat::FLAGS_SUPPORTED {
+        die("--file-flags", "not supported on this platform");
+    }
 
     configure_thread_pool(&opt);
 
-    if opt.dirs.is_empty() {
-        let path = Path::new(".");
-        let checksum = checksum_current_dir(path, opt.ignore_unknown_filetypes);
-        let _ = writeln!(io::stdout(), "{}", checksum);
+    match &opt.command {
+        Some(Command::Bench(args)) => bench::run(&opt, args),
+        Some(Command::Diff(args)) => diff::run(&opt, args),
+        Some(Command::ExportCas(args)) => cas::run(&opt, args),
+        Some(Command::GitVerify(args)) => git::run(&opt, args),
+        Some(Command::Manpage) => manpage::run(),
+        Some(Command::RemoteDiff(args)) => remote::run(&opt, args),
+        Some(Command::Update(args)) => check::update(&opt, &args.file),
+        Some(Command::Hash(_) | Command::Verify(_)) => unreachable!(),
+        None => {}
+    }
+
+    if let Some(path) = &opt.check {
+        check::run(&opt, path);
+    }
+
+    if opt.format == Format::Nar {
+        check_nar_conflicts(&opt);
+        print_each(&opt, |dir| nar::nar_hash(dir, opt.ignore_unknown_filetypes));
         return;
     }
 
-    let absolute_dirs: Vec<_> = opt.dirs.iter().map(canonicalize).collect();
-    for (canonical, label) in absolute_dirs.into_iter().zip(opt.dirs) {
-        debug_assert!(canonical.is_absolute());
-        if let Err(error) = env::set_current_dir(canonical) {
+    if let Some(Compat::CatPipeline) = opt.compat {
+        print_each(&opt, compat::cat_pipeline);
+        return;
+    }
+
+    if opt.estimate {
+        dry_run_main(&opt, || DryRun::Estimate(Totals::default()));
+    }
+    if opt.list {
+        dry_run_main(&opt, || DryRun::List(Mutex::new(Vec::new())));
+    }
+
+    checksum_main(&opt);
+}
+
+fn checksum_main(opt: &Opt) {
+    let secret_key = opt
+        .sign
+        .as_ref()
+        .map(|path| match sign::load_secret_key(path) {
+            Ok(secret_key) => secret_key,
+            Err(error) => die(path, error),
+        });
+
+    let audit_log = open_audit_log(opt);
+
+    let default_dirs = [PathBuf::from(".")];
+    let dirs = if opt.dirs.is_empty() {
+        &default_dirs
+    } else {
+        opt.dirs.as_slice()
+    };
+
+    if !opt.expect.is_empty() && opt.expect.len() != dirs.len() {
+        let message = "--expect must be given once for each DIR";
+        Opt::command()
+            .error(ErrorKind::WrongNumberOfValues, message)
+            .exit();
+    }
+
+    let archive_format = if opt.tar {
+        Some(archive::Format::Tar)
+    } else if opt.cpio {
+        Some(archive::Format::Cpio)
+    } else {
+        None
+    };
+
+    let absolute_dirs: Vec<_> = if archive_format.is_some() {
+        dirs.to_vec()
+    } else {
+        dirs.iter().map(canonicalize).collect()
+    };
+    let state = open_state(opt, &absolute_dirs);
+    let print_manifest = match opt.manifest_format {
+        Some(ManifestFormat::Sha1dir) => true,
+        Some(ManifestFormat::Spdx) => false,
+        None => opt.manifest,
+    };
+    let print_spdx = opt.manifest_format == Some(ManifestFormat::Spdx);
+    let eol = opt.eol();
+    let mut next_spdx_id = 1;
+    let mut attested = Vec::new();
+    let mut trees = Vec::new();
+    let mut chunk_lists = Vec::new();
+    // Output that is signed or written to --output is held until the end.
+    let buffer = secret_key.is_some() || opt.gpg_sign.is_some() || opt.output.is_some();
+    let mut buffered = Vec::new();
+    let mut failed = false;
+    let limits = Limits::new(opt);
+    let cxs: Vec<Context> = absolute_dirs
+        .iter()
+        .zip(dirs)
+        .map(|(canonical, label)| {
+            let mut cx = Context::new(opt, label, canonical, state.as_ref(), &limits);
+            cx.audit_log = audit_log.as_ref();
+            if print_manifest || opt.attestation.is_some() || opt.merkle.is_some() {
+                cx.entries = Some(Mutex::new(Vec::new()));
+            }
+            if opt.per_entry {
+                cx.per_entry = Some(Mutex::new(HashMap::new()));
+            }
+            if opt.rollup_depth.is_some() {
+                cx.rollups = Some(Mutex::new(HashMap::new()));
+            }
+            if print_spdx {
+                cx.spdx = Some(SpdxFiles::new());
+            }
+            if let Some(chunk_size) = opt.etag_compat {
+                cx.etags = Some(Etags::new(chunk_size));
+            }
+            if opt.chunked_manifest.is_some() {
+                cx.chunk_lists = Some(ChunkLists::new());
+            }
+            cx
+        })
+        .collect();
+    if let Some(format) = archive_format {
+        for cx in &cxs {
+            archive::checksum_archive(cx, Some(format));
+        }
+    } else {
+        // All directories at once, with output in the order given.
+        walk::checksum_dirs(&cxs);
+    }
+
+    for (i, (cx, label)) in cxs.into_iter().zip(dirs).enumerate() {
+        let mut stdout = io::stdout().lock();
+        let out: &mut dyn Write = if buffer { &mut buffered } else { &mut stdout };
+        let manifest = cx.entries.map(|entries| {
+            let mut entries = entries.into_inner();
+            manifest::sort(&mut entries);
+            Manifest {
+                label: label.clone(),
+                checksum: cx.checksum.bytes(),
+                entries,
+            }
+        });
+        let result = if let (true, Some(manifest)) = (print_manifest, &manifest) {
+            manifest::write(out, manifest, eol)
+        } else if let Some(spdx) = cx.spdx {
+            spdx.write(out, &mut next_spdx_id)
+        } else if let Some(expected) = opt.expect.get(i) {
+            let matches = ct_eq(&cx.checksum.bytes(), expected);
+            failed |= !matches;
+            let status = if matches { "OK" } else { "FAILED" };
+            out.write_all(label.as_os_str().as_bytes())
+                .and_then(|()| write!(out, ": {}", status))
+                .and_then(|()| out.write_all(&[eol]))
+                .map_err(Into::into)
+        } else {
+            let totals = opt.totals.then(|| cx.checksum.totals());
+            let label = (!opt.dirs.is_empty()).then_some(label.as_path());
+            write_checksum(out, &cx.checksum, totals.as_deref(), label, eol)
+        };
+        let result = result.and_then(|()| match cx.per_entry {
+            Some(per_entry) => write_subtrees(out, per_entry.into_inner(), label, opt.totals, eol),
+            None => Ok(()),
+        });
+        let result = result.and_then(|()| match cx.rollups {
+            Some(rollups) => write_subtrees(out, rollups.into_inner(), label, opt.totals, eol),
+            None => Ok(()),
+        });
+        let result = result.and_then(|()| match cx.etags {
+            Some(etags) => etags.write(out, label, eol),
+            None => Ok(()),
+        });
+        if let Err(error) = result {
             die(label, error);
         }
-        let checksum = checksum_current_dir(&label, opt.ignore_unknown_filetypes);
-        let _ = writeln!(io::stdout(), "{}  {}", checksum, label.display());
+        if let (Some(_), Some(manifest)) = (&opt.merkle, &manifest) {
+            trees.push(merkle::tree(manifest));
+        }
+        if let Some(lists) = cx.chunk_lists {
+            chunk_lists.push((label.as_path(), lists));
+        }
+        if opt.attestation.is_some() {
+            attested.extend(manifest);
+        }
+    }
+
+    if let Some(path) = &opt.merkle {
+        if let Err(error) = merkle::write(path, &trees, eol) {
+            die(path, error);
+        }
+    }
+
+    if let Some(path) = &opt.chunked_manifest {
+        if let Err(error) = cdc::write(path, chunk_lists, eol) {
+            die(path, error);
+        }
+    }
+
+    if let Some(path) = &opt.attestation {
+        if let Err(error) = attestation::write(path, &attested) {
+            die(path, error);
+        }
+    }
+
+    if let (Some(secret_key), Some(path)) = (&secret_key, &opt.sign) {
+        let signature = match sign::sign(secret_key, &buffered) {
+            Ok(signature) => signature,
+            Err(error) => die(path, error),
+        };
+        buffered.extend_from_slice(signature.as_bytes());
+    }
+
+    if let Some(key_id) = &opt.gpg_sign {
+        let signature = match gpg::sign(key_id, &buffered) {
+            Ok(signature) => signature,
+            Err(error) => die(key_id, error),
+        };
+        buffered.extend_from_slice(&signature);
+    }
+
+    if let Some(path) = &opt.output {
+        if let Err(error) = write_atomic(path, &buffered) {
+            die(path, error);
+        }
+    } else if buffer {
+        let _ = io::stdout().lock().write_all(&buffered);
+    }
+
+    finish_state(state.as_ref());
+
+    if failed {
+        process::exit(1);
     }
 }
 
-fn configure_thread_pool(opt: &Opt) {
-    let threads = if let Some(jobs) = opt.jobs {
-        jobs
+// For --per-entry and --rollup-depth.
+fn write_subtrees(
+    out: &mut dyn Write,
+    subtrees: HashMap<PathBuf, Checksum>,
+    label: &Path,
+    totals: bool,
+    eol: u8,
+) -> Result<()> {
+    let mut subtrees = Vec::from_iter(subtrees);
+    subtrees.sort_unstable_by(|a, b| a.0.as_os_str().cmp(b.0.as_os_str()));
+    for (path, checksum) in subtrees {
+        let totals = totals.then(|| checksum.totals());
+        let path = label.join(path);
+        write_checksum(out, &checksum, totals.as_deref(), Some(&path), eol)?;
+    }
+    Ok(())
+}
+
+// The path is written as is, or escaped as in a manifest, rather than as
+// displayed, so that any name can be told apart.
+fn write_checksum(
+    out: &mut dyn Write,
+    checksum: &Checksum,
+    totals: Option<&str>,
+    path: Option<&Path>,
+    eol: u8,
+) -> Result<()> {
+    let (prefix, path) = match path {
+        Some(path) => manifest::escape(path, eol),
+        None => (&b""[..], Cow::Borrowed(&b""[..])),
+    };
+    out.write_all(prefix)?;
+    write!(out, "{}", checksum)?;
+    if let Some(totals) = totals {
+        write!(out, "  {}", totals)?;
+    }
+    if !path.is_empty() {
+        out.write_all(b"  ")?;
+        out.write_all(&path)?;
+    }
+    out.write_all(&[eol])?;
+    Ok(())
+}
+
+fn check_nar_conflicts(opt: &Opt) {
+    if opt.manifest
+        || opt.manifest_format.is_some()
+        || opt.etag_compat.is_some()
+        || opt.tar
+        || opt.cpio
+        || opt.state.is_some()
+        || opt.resume.is_some()
+        || opt.attestation.is_some()
+        || opt.sign.is_some()
+        || opt.gpg_sign.is_some()
+        || opt.audit_log.is_some()
+        || !opt.expect.is_empty()
+        || opt.shard.is_some()
+        || opt.bwlimit.is_some()
+        || opt.chunked.is_some()
+        || opt.totals
+        || opt.xattrs
+        || opt.file_flags
+        || opt.combine != Combine::Xor
+        || opt.merkle.is_some()
+        || opt.per_entry
+        || opt.rollup_depth.is_some()
+        || opt.chunked_manifest.is_some()
+    {
+        let message = "--format nar cannot be combined with --manifest, --manifest-format, --etag-compat, --tar, --cpio, --state, --resume, --attestation, --sign, --gpg-sign, --audit-log, --expect, --shard, --bwlimit, --chunked, --totals, --xattrs, --file-flags, --combine, --merkle, --per-entry, --rollup-depth or --chunked-manifest";
+        Opt::command()
+            .error(ErrorKind::ArgumentConflict, message)
+            .exit();
+    }
+}
+
+// Walks every DIR as for hashing, but reads no content.
+fn dry_run_main(opt: &Opt, dry_run: fn() -> DryRun) -> ! {
+    let default_dirs = [PathBuf::from(".")];
+    let dirs = if opt.dirs.is_empty() {
+        &default_dirs
     } else {
-        // Limit to 8 threads by default to avoid thrashing disk.
-        cmp::min(num_cpus::get(), 8)
+        opt.dirs.as_slice()
+    };
+
+    let absolute_dirs: Vec<_> = dirs.iter().map(canonicalize).collect();
+    let limits = Limits::new(opt);
+    let cxs: Vec<Context> = absolute_dirs
+        .iter()
+        .zip(dirs)
+        .map(|(canonical, label)| {
+            let mut cx = Context::new(opt, label, canonical, None, &limits);
+            cx.dry_run = Some(dry_run());
+            cx
+        })
+        .collect();
+    walk::checksum_dirs(&cxs);
+
+    let mut stdout = io::BufWriter::new(io::stdout().lock());
+    for (cx, label) in cxs.into_iter().zip(dirs) {
+        let shown = if opt.dirs.is_empty() {
+            None
+        } else {
+            Some(label.as_path())
+        };
+        let result = cx.dry_run.unwrap().write(opt, &mut stdout, shown);
+        if let Err(error) = result.and_then(|()| stdout.flush()) {
+            die(label, error);
+        }
+    }
+
+    process::exit(0);
+}
+
+// For the checksums computed by walking each directory by itself, without
+// the machinery of the default checksum.
+fn print_each(opt: &Opt, checksum: impl Fn(&Path) -> String) {
+    let mut stdout = io::stdout().lock();
+    if opt.dirs.is_empty() {
+        let checksum = checksum(Path::new("."));
+        let _ = write!(stdout, "{}", checksum);
+        let _ = stdout.write_all(&[opt.eol()]);
+        return;
+    }
+
+    for dir in &opt.dirs {
+        let checksum = checksum(dir);
+        let _ = write!(stdout, "{}  ", checksum);
+        let _ = stdout.write_all(dir.as_os_str().as_bytes());
+        let _ = stdout.write_all(&[opt.eol()]);
+    }
+}
+
+// Either a directory or a tar archive.
+fn manifest_of(
+    opt: &Opt,
+    label: &Path,
+    canonical: &Path,
+    audit_log: Option<&AuditLog>,
+) -> Manifest {
+    let limits = Limits::new(opt);
+    let mut cx = Context::new(opt, label, canonical, None, &limits);
+    cx.audit_log = audit_log;
+    cx.entries = Some(Mutex::new(Vec::new()));
+    if canonical.is_dir() {
+        walk::checksum_dirs(slice::from_ref(&cx));
+    } else {
+        archive::checksum_archive(&cx, None);
+    }
+    let mut entries = cx.entries.unwrap().into_inner();
+    manifest::sort(&mut entries);
+    Manifest {
+        label: label.to_owned(),
+        checksum: cx.checksum.bytes(),
+        entries,
+    }
+}
+
+fn open_state(opt: &Opt, roots: &[PathBuf]) -> Option<State> {
+    if opt.state.is_none() && opt.resume.is_none() {
+        return None;
+    }
+
+    // Everything besides the file that goes into the digest of a file.
+    let options = [
+        ("xattrs", opt.xattrs.to_string()),
+        ("file-flags", opt.file_flags.to_string()),
+        ("format-version", opt.format_version.to_string()),
+    ];
+    let header = state::header(&options, roots);
+
+    let (path, result) = if let Some(path) = &opt.state {
+        (path, State::create(path, &header))
+    } else {
+        let path = opt.resume.as_ref()?;
+        (path, State::resume(path, &header))
+    };
+
+    match result {
+        Ok(state) => Some(state),
+        Err(error) => die(path, error),
+    }
+}
+
+fn open_audit_log(opt: &Opt) -> Option<AuditLog> {
+    let path = opt.audit_log.as_ref()?;
+    match AuditLog::open(path) {
+        Ok(audit_log) => Some(audit_log),
+        Err(error) => die(path, error),
+    }
+}
+
+fn finish_state(state: Option<&State>) {
+    if let Some(state) = state {
+        if let Err(error) = state.flush() {
+            die(state.path(), error);
+        }
+    }
+}
+
+fn configure_thread_pool(opt: &Opt) {
+    // Used for whatever is not hashed by the per-device threads of the
+    // directory traversal, which are sized by the kind of storage by default.
+    let threads = match opt.jobs {
+        Some(Jobs::Threads(threads)) => threads,
+        Some(Jobs::Auto) => num_cpus::get(),
+        // Limit to 8 threads by default to avoid thrashing disk. The count of
+        // CPUs takes into account the affinity mask and any cgroup v1 or v2
+        // CPU quota, since num_cpus 1.14, so a container limited to 2 CPUs
+        // gets 2 threads.
+        None => cmp::min(num_cpus::get(), 8),
     };
 
     let result = ThreadPoolBuilder::new().num_threads(threads).build_global();
 
     // This is the only time the thread pool is initialized.
     result.unwrap();
+
+    walk::configure(opt.walk_jobs.unwrap_or(threads), opt.jobs, opt.traversal);
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Jobs {
+    Auto,
+    Threads(usize),
+}
+
+fn parse_jobs(arg: &str) -> std::result::Result<Jobs, String> {
+    if arg == "auto" {
+        return Ok(Jobs::Auto);
+    }
+    match arg.parse() {
+        Ok(0) | Err(_) => Err("expected a positive number or `auto`".to_owned()),
+        Ok(threads) => Ok(Jobs::Threads(threads)),
+    }
+}
+
+fn parse_size(arg: &str) -> std::result::Result<u64, String> {
+    let digits = arg
+        .find(|ch: char| !ch.is_ascii_digit())
+        .unwrap_or(arg.len());
+    let (number, suffix) = arg.split_at(digits);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {}", arg))?;
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("invalid size suffix: {}", suffix)),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {}", arg))
+}
+
+fn parse_nonzero_count(arg: &str) -> std::result::Result<usize, String> {
+    match arg.parse() {
+        Ok(0) | Err(_) => Err("expected a positive number".to_owned()),
+        Ok(count) => Ok(count),
+    }
+}
+
+fn parse_digest(arg: &str) -> std::result::Result<[u8; 20], String> {
+    parse_hex(arg.as_bytes()).ok_or_else(|| "expected 40 hex digits".to_owned())
+}
+
+fn parse_shard(arg: &str) -> std::result::Result<Shard, String> {
+    let (k, n) = arg
+        .split_once('/')
+        .ok_or_else(|| "expected K/N".to_owned())?;
+    let k: u64 = k.parse().map_err(|_| format!("invalid shard: {}", k))?;
+    let n: u64 = n
+        .parse()
+        .map_err(|_| format!("invalid shard count: {}", n))?;
+    if k == 0 || k > n {
+        return Err(format!("shard must be between 1 and {}", n));
+    }
+    Ok(Shard {
+        index: k - 1,
+        count: n,
+    })
+}
+
+fn parse_percent(arg: &str) -> std::result::Result<f64, String> {
+    let percent: f64 = arg
+        .strip_suffix('%')
+        .unwrap_or(arg)
+        .parse()
+        .map_err(|_| format!("invalid percentage: {}", arg))?;
+    if percent > 0.0 && percent <= 100.0 {
+        Ok(percent)
+    } else {
+        Err("percentage must be greater than 0 and at most 100".to_owned())
+    }
+}
+
+fn parse_nonzero_size(arg: &str) -> std::result::Result<u64, String> {
+    match parse_size(arg)? {
+        0 => Err("size must be greater than zero".to_owned()),
+        size => Ok(size),
+    }
+}
+
+// Built without mmap, a file is "mapped" by reading all of it into memory.
+#[cfg(not(feature = "mmap"))]
+type Mmap = Vec<u8>;
+
+fn map(path: &Path) -> io::Result<Option<Mmap>> {
+    map_file(&File::open(path)?)
+}
+
+#[cfg(feature = "mmap")]
+fn map_file(file: &File) -> io::Result<Option<Mmap>> {
+    // Enforced by memmap: "memory map must have a non-zero length"
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(unsafe { Mmap::map(file)? }))
+}
+
+#[cfg(not(feature = "mmap"))]
+fn map_file(mut file: &File) -> io::Result<Option<Mmap>> {
+    use std::io::Read;
+
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok(Some(content))
+}
+
+#[cfg(target_os = "linux")]
+fn open(at: At, noatime: bool) -> io::Result<File> {
+    if noatime {
+        // Only permitted for the owner of the file or with CAP_FOWNER.
+        match at.open(libc::O_NOATIME) {
+            Err(error) if error.raw_os_error() == Some(libc::EPERM) => {}
+            result => return result,
+        }
+    }
+    at.open(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open(at: At, _noatime: bool) -> io::Result<File> {
+    at.open(0)
+}
+
+// Written beside the destination and renamed over it, so that it is never left
+// partly written, even by a crash.
+fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let result = File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
 }
 
 fn canonicalize<P: AsRef<Path>>(path: P) -> PathBuf {
@@ -112,160 +1329,650 @@ fn canonicalize<P: AsRef<Path>>(path: P) -> PathBuf {
 }
 
 struct Checksum {
-    bytes: Mutex<[u8; 20]>,
+    xor: Mutex<[u8; 20]>,
+    // Used in place of the XOR, for --combine lthash.
+    lthash: Option<Mutex<LtHash>>,
+    // Number of entries put, and the total size of the regular files among
+    // them, for --totals.
+    entries: AtomicU64,
+    size: AtomicU64,
 }
 
 impl Checksum {
-    fn new() -> Self {
+    fn new(combine: Combine) -> Self {
         Checksum {
-            bytes: Mutex::new([0u8; 20]),
+            xor: Mutex::new([0u8; 20]),
+            lthash: match combine {
+                Combine::Xor => None,
+                Combine::Lthash => Some(Mutex::new(LtHash::new())),
+            },
+            entries: AtomicU64::new(0),
+            size: AtomicU64::new(0),
         }
     }
 }
 
 impl Display for Checksum {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for i in self.bytes.lock().as_ref() {
+        Display::fmt(&Hex(&self.bytes()), f)
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for i in self.0 {
             write!(f, "{:02x}", i)?;
         }
         Ok(())
     }
 }
 
+// Digests being verified may come from an untrusted manifest.
+fn ct_eq(lhs: &[u8; 20], rhs: &[u8; 20]) -> bool {
+    sha1dir::Checksum::from(*lhs).ct_eq(&sha1dir::Checksum::from(*rhs))
+}
+
+fn parse_hex(hex: &[u8]) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut bytes = [0u8; 20];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
 impl Checksum {
-    fn put(&self, rhs: Sha1) {
-        for (lhs, rhs) in self.bytes.lock().iter_mut().zip(rhs.finalize()) {
-            *lhs ^= rhs;
-        }
-    }
-}
-
-fn checksum_current_dir(label: &Path, ignore_unknown_filetypes: bool) -> Checksum {
-    let checksum = Checksum::new();
-    rayon::scope(|scope| {
-        if let Err(error) = (|| -> Result<()> {
-            for child in Path::new(".").read_dir()? {
-                let child = child?;
-                scope.spawn({
-                    let checksum = &checksum;
-                    move |scope| {
-                        entry(
-                            scope,
-                            label,
-                            checksum,
-                            Path::new(&child.file_name()),
-                            ignore_unknown_filetypes,
-                        );
-                    }
-                });
+    fn put(&self, rhs: [u8; 20]) {
+        if let Some(lthash) = &self.lthash {
+            let expanded = lthash::expand(&rhs);
+            lthash.lock().add(&expanded);
+        } else {
+            for (lhs, rhs) in self.xor.lock().iter_mut().zip(rhs) {
+                *lhs ^= rhs;
             }
-            Ok(())
-        })() {
-            die(label, error);
         }
-    });
-    checksum
+        self.entries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn add_size(&self, size: u64) {
+        self.size.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn totals(&self) -> String {
+        let entries = self.entries.load(Ordering::Relaxed);
+        let size = self.size.load(Ordering::Relaxed);
+        format!("{} entries, {} bytes", entries, size)
+    }
+
+    fn bytes(&self) -> [u8; 20] {
+        match &self.lthash {
+            Some(lthash) => lthash.lock().digest(),
+            None => *self.xor.lock(),
+        }
+    }
 }
 
-fn entry<'scope>(
-    scope: &Scope<'scope>,
-    base: &'scope Path,
-    checksum: &'scope Checksum,
-    path: &Path,
+struct Context<'a> {
+    label: &'a Path,
+    root: &'a Path,
+    checksum: Checksum,
     ignore_unknown_filetypes: bool,
-) {
-    let metadata = match path.symlink_metadata() {
-        Ok(metadata) => metadata,
-        Err(error) => die(base.join(path), error),
-    };
+    skip_doors: bool,
+    state: Option<&'a State>,
+    audit_log: Option<&'a AuditLog>,
+    shard: Option<Shard>,
+    noatime: bool,
+    drop_cache: bool,
+    readahead: bool,
+    direct_io: bool,
+    // Files shorter than this are read rather than mapped.
+    mmap_threshold: u64,
+    throttle: Option<&'a Throttle>,
+    map_budget: Option<&'a MapBudget>,
+    chunked: Option<u64>,
+    order: Option<Order>,
+    xattrs: bool,
+    // The ctime is needed by the state file.
+    stat_fields: stat::Fields,
+    entries: Option<Mutex<Vec<Entry>>>,
+    // Keyed by the first component of the path, for --per-entry.
+    per_entry: Option<Mutex<HashMap<PathBuf, Checksum>>>,
+    // Keyed by each directory down to this depth, for --rollup-depth.
+    rollup_depth: usize,
+    rollups: Option<Mutex<HashMap<PathBuf, Checksum>>>,
+    combine: Combine,
+    etags: Option<Etags>,
+    spdx: Option<SpdxFiles>,
+    chunk_lists: Option<ChunkLists>,
+    cas: Option<Cas>,
+    dry_run: Option<DryRun>,
+}
 
-    let file_type = metadata.file_type();
-    let result = if file_type.is_file() {
-        file(checksum, path, metadata)
-    } else if file_type.is_symlink() {
-        symlink(checksum, path, metadata)
-    } else if file_type.is_dir() {
-        dir(
-            scope,
-            base,
-            checksum,
-            path,
-            ignore_unknown_filetypes,
-            metadata,
-        )
-    } else if file_type.is_socket() {
-        socket(checksum, path, metadata)
-    } else if ignore_unknown_filetypes {
-        Ok(())
+// For --bwlimit and --max-map-memory, which limit a run as a whole however
+// many directories it hashes at once.
+struct Limits {
+    throttle: Option<Throttle>,
+    map_budget: Option<MapBudget>,
+}
+
+impl Limits {
+    fn new(opt: &Opt) -> Self {
+        Limits {
+            throttle: opt.bwlimit.map(Throttle::new),
+            map_budget: opt.max_map_memory.map(MapBudget::new),
+        }
+    }
+}
+
+// What is gathered instead of hashing, by --estimate or --list.
+enum DryRun {
+    Estimate(Totals),
+    List(Mutex<Vec<PathBuf>>),
+}
+
+impl DryRun {
+    fn add(&self, path: &Path, metadata: &Stat) {
+        match self {
+            DryRun::Estimate(totals) => totals.add(metadata),
+            DryRun::List(paths) => paths.lock().push(path.to_owned()),
+        }
+    }
+
+    fn write(self, opt: &Opt, out: &mut dyn Write, label: Option<&Path>) -> io::Result<()> {
+        match self {
+            DryRun::Estimate(totals) => estimate::write(out, totals, opt.estimate_rate, label),
+            DryRun::List(paths) => {
+                let mut paths = paths.into_inner();
+                paths.sort_unstable_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+                list::write(out, &paths, label, opt.eol())
+            }
+        }
+    }
+}
+
+impl<'a> Context<'a> {
+    fn new(
+        opt: &Opt,
+        label: &'a Path,
+        root: &'a Path,
+        state: Option<&'a State>,
+        limits: &'a Limits,
+    ) -> Self {
+        Context {
+            label,
+            root,
+            checksum: Checksum::new(opt.combine),
+            ignore_unknown_filetypes: opt.ignore_unknown_filetypes,
+            skip_doors: opt.skip_doors,
+            state,
+            audit_log: None,
+            shard: opt.shard,
+            noatime: opt.noatime,
+            drop_cache: opt.drop_cache,
+            readahead: opt.readahead,
+            direct_io: opt.direct_io,
+            mmap_threshold: if opt.no_mmap || cfg!(not(feature = "mmap")) {
+                u64::MAX
+            } else {
+                opt.mmap_threshold.unwrap_or(0)
+            },
+            throttle: limits.throttle.as_ref(),
+            map_budget: limits.map_budget.as_ref(),
+            chunked: opt.chunked,
+            order: opt.order,
+            xattrs: opt.xattrs,
+            stat_fields: stat::Fields {
+                ctime: state.is_some(),
+                flags: opt.file_flags,
+            },
+            entries: None,
+            per_entry: None,
+            rollup_depth: opt.rollup_depth.map_or(0, |depth| depth as usize),
+            rollups: None,
+            combine: opt.combine,
+            etags: None,
+            spdx: None,
+            chunk_lists: None,
+            cas: None,
+            dry_run: None,
+        }
+    }
+
+    fn audit(&self, opened: SystemTime, path: &Path, result: std::result::Result<u64, &io::Error>) {
+        if let Some(audit_log) = self.audit_log {
+            // An empty path is the archive itself, whose root is as given on
+            // the command line.
+            let absolute = if path.as_os_str().is_empty() {
+                fs::canonicalize(self.root).unwrap_or_else(|_| self.root.to_owned())
+            } else {
+                self.root.join(path)
+            };
+            if let Err(error) = audit_log.record(opened, &absolute, result) {
+                die(audit_log.path(), error);
+            }
+        }
+    }
+
+    fn in_shard(&self, path: &Path) -> bool {
+        match self.shard {
+            Some(shard) => shard.contains(path),
+            None => true,
+        }
+    }
+
+    // Reading happens as the hash pulls pages of the mapping in.
+    fn update(&self, sha: &mut Sha1, content: &[u8]) {
+        if let Some(throttle) = &self.throttle {
+            for chunk in content.chunks(throttle::CHUNK_SIZE) {
+                throttle.acquire(chunk.len());
+                sha.update(chunk);
+            }
+        } else {
+            sha.update(content);
+        }
+    }
+
+    fn put(&self, path: &Path, sha: Sha1) {
+        self.put_digest(path, sha.finalize().into());
+    }
+
+    // A directory's own digest is part of its rollup too, which gives one even
+    // to an empty directory.
+    fn put_dir(&self, path: &Path, sha: Sha1) {
+        let digest = sha.finalize().into();
+        self.put_digest(path, digest);
+        self.rollup(path, |checksum| checksum.put(digest));
+    }
+
+    fn add_size(&self, path: &Path, size: u64) {
+        self.checksum.add_size(size);
+        self.subtrees(path, |checksum| checksum.add_size(size));
+    }
+
+    // Applies `f` to each checksum of --per-entry or --rollup-depth that
+    // covers the entry at `path`.
+    fn subtrees(&self, path: &Path, f: impl Fn(&Checksum)) {
+        if let (Some(per_entry), Some(Component::Normal(top))) =
+            (&self.per_entry, path.components().next())
+        {
+            subtree(per_entry, Path::new(top), self.combine, &f);
+        }
+        if self.rollups.is_some() {
+            for dir in path.ancestors().skip(1) {
+                self.rollup(dir, &f);
+            }
+        }
+    }
+
+    fn rollup(&self, dir: &Path, f: impl Fn(&Checksum)) {
+        let Some(rollups) = &self.rollups else {
+            return;
+        };
+        let depth = dir.components().count();
+        if depth > 0 && depth <= self.rollup_depth {
+            subtree(rollups, dir, self.combine, f);
+        }
+    }
+
+    fn put_digest(&self, path: &Path, digest: [u8; 20]) {
+        self.checksum.put(digest);
+        self.subtrees(path, |checksum| checksum.put(digest));
+        if let Some(entries) = &self.entries {
+            entries.lock().push(Entry {
+                path: path.to_owned(),
+                digest,
+            });
+        }
+    }
+}
+
+fn subtree(
+    subtrees: &Mutex<HashMap<PathBuf, Checksum>>,
+    path: &Path,
+    combine: Combine,
+    f: impl Fn(&Checksum),
+) {
+    let mut subtrees = subtrees.lock();
+    if let Some(checksum) = subtrees.get(path) {
+        f(checksum);
     } else {
-        die(base.join(path), "Unsupported file type");
+        let checksum = Checksum::new(combine);
+        f(&checksum);
+        subtrees.insert(path.to_owned(), checksum);
+    }
+}
+
+fn file(cx: &Context, path: &Path, at: At, metadata: Stat) -> Result<()> {
+    // Computing ETags, SPDX checksums or chunks requires reading the content,
+    // so it can't be skipped.
+    let state = cx
+        .state
+        .filter(|_| cx.etags.is_none() && cx.spdx.is_none() && cx.chunk_lists.is_none());
+    let absolute = state.map(|_| cx.root.join(path));
+    if let (Some(state), Some(absolute)) = (state, &absolute) {
+        if let Some(digest) = state.lookup(absolute, &metadata) {
+            cx.add_size(path, metadata.size);
+            cx.put_digest(path, digest);
+            return Ok(());
+        }
+    }
+
+    if let Some(chunk_size) = cx.chunked.filter(|&size| metadata.size > size) {
+        let opened = SystemTime::now();
+        let hashed = open(at, cx.noatime).and_then(|file| {
+            let mut digests = Vec::new();
+            // Windows start on a chunk boundary.
+            let window = cmp::max(MAP_WINDOW / chunk_size, 1) * chunk_size;
+            map_content(cx, &file, window, |content| {
+                digests.extend(chunk_digests(cx, content, chunk_size));
+            })?;
+            let sha = begin_entry(cx, path, at, &metadata, b'c')?;
+            Ok(chunked(sha, &digests, chunk_size))
+        });
+        return match hashed {
+            Ok(sha) => {
+                cx.audit(opened, path, Ok(metadata.size));
+                cx.add_size(path, metadata.size);
+                record(cx, state, absolute.as_deref(), path, &metadata, sha);
+                Ok(())
+            }
+            Err(error) => {
+                cx.audit(opened, path, Err(&error));
+                Err(error.into())
+            }
+        };
+    }
+
+    let mut sha = begin_entry(cx, path, at, &metadata, b'f')?;
+
+    let mut etag = cx.etags.as_ref().map(Etags::start);
+    let mut checksums = cx.spdx.as_ref().map(|_| Checksums::new());
+    let mut chunker = cx.chunk_lists.as_ref().map(|_| Chunker::new());
+    let mut blob = cx.cas.as_ref().map(|_| Sha1::new());
+    let mut consume = |chunk: &[u8]| {
+        cx.update(&mut sha, chunk);
+        if let Some(etag) = &mut etag {
+            etag.update(chunk);
+        }
+        if let Some(checksums) = &mut checksums {
+            checksums.update(chunk);
+        }
+        if let Some(chunker) = &mut chunker {
+            chunker.update(chunk);
+        }
+        if let Some(blob) = &mut blob {
+            blob.update(chunk);
+        }
     };
 
-    if let Err(error) = result {
-        die(base.join(path), error);
+    let opened = SystemTime::now();
+    match read_content(cx, at, &metadata, &mut consume) {
+        Ok(len) => {
+            cx.audit(opened, path, Ok(len));
+            cx.add_size(path, len);
+        }
+        Err(error) => {
+            cx.audit(opened, path, Err(&error));
+            return Err(error.into());
+        }
     }
+    if let (Some(etags), Some(etag)) = (&cx.etags, etag) {
+        etags.finish(path, etag);
+    }
+    if let (Some(spdx), Some(checksums)) = (&cx.spdx, checksums) {
+        spdx.finish(path, checksums);
+    }
+    if let (Some(lists), Some(chunker)) = (&cx.chunk_lists, chunker) {
+        lists.finish(path, chunker);
+    }
+    if let (Some(cas), Some(blob)) = (&cx.cas, blob) {
+        cas.add_file(path, &cx.root.join(path), blob.finalize().into())?;
+    }
+
+    record(cx, state, absolute.as_deref(), path, &metadata, sha);
+    Ok(())
 }
 
-fn file(checksum: &Checksum, path: &Path, metadata: Metadata) -> Result<()> {
-    let mut sha = begin(path, &metadata, b'f');
+// Passes the content to `consume` in one or more pieces and returns its
+// length.
+fn read_content(
+    cx: &Context,
+    at: At,
+    metadata: &Stat,
+    consume: &mut dyn FnMut(&[u8]),
+) -> io::Result<u64> {
+    if cx.direct_io {
+        return read::direct(at, cx.noatime, consume);
+    }
 
     // Enforced by memmap: "memory map must have a non-zero length"
-    if metadata.len() > 0 {
-        let file = File::open(path)?;
-        let mmap = unsafe { Mmap::map(&file)? };
-        sha.update(&mmap);
+    if metadata.size == 0 {
+        return Ok(0);
     }
 
-    checksum.put(sha);
+    let file = open(at, cx.noatime)?;
+    if metadata.size < cx.mmap_threshold {
+        return read::buffered(file, consume);
+    }
+
+    let mut len = 0;
+    map_content(cx, &file, MAP_WINDOW, |content| {
+        consume(content);
+        len += content.len() as u64;
+    })?;
+    Ok(len)
+}
 
+// Maps the file one window at a time, passing each to `f` in order.
+#[cfg(feature = "mmap")]
+fn map_content(cx: &Context, file: &File, window: u64, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    // The file's length now rather than when it was stat'ed, as mapping past
+    // the end would fault.
+    let len = file.metadata()?.len();
+    let mut offset = 0;
+    while offset < len {
+        let window = cmp::min(window, len - offset);
+        let reservation = cx.map_budget.as_ref().map(|budget| budget.reserve(window));
+        let mmap = unsafe {
+            MmapOptions::new()
+                .offset(offset)
+                .len(window as usize)
+                .map(file)?
+        };
+        advice::sequential(file, &mmap);
+        f(&mmap);
+        drop(mmap);
+        drop(reservation);
+        offset += window;
+    }
+    if cx.drop_cache {
+        advice::drop_cache(file);
+    }
     Ok(())
 }
 
-fn symlink(checksum: &Checksum, path: &Path, metadata: Metadata) -> Result<()> {
-    let mut sha = begin(path, &metadata, b'l');
-    sha.update(path.read_link()?.as_os_str().as_bytes());
-    checksum.put(sha);
+// Reads the file one window at a time instead, for --chunked.
+#[cfg(not(feature = "mmap"))]
+fn map_content(cx: &Context, file: &File, window: u64, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    use std::io::Read;
 
+    advice::sequential(file);
+    let mut buf = Vec::new();
+    loop {
+        let reservation = cx.map_budget.as_ref().map(|budget| budget.reserve(window));
+        buf.clear();
+        file.take(window).read_to_end(&mut buf)?;
+        if buf.is_empty() {
+            break;
+        }
+        f(&buf);
+        drop(reservation);
+    }
+    if cx.drop_cache {
+        advice::drop_cache(file);
+    }
     Ok(())
 }
 
-fn dir<'scope>(
-    scope: &Scope<'scope>,
-    base: &'scope Path,
-    checksum: &'scope Checksum,
+fn chunk_digests(cx: &Context, content: &[u8], chunk_size: u64) -> Vec<[u8; 20]> {
+    content
+        .par_chunks(chunk_size as usize)
+        .map(|chunk| {
+            let mut sha = Sha1::new();
+            cx.update(&mut sha, chunk);
+            sha.finalize().into()
+        })
+        .collect()
+}
+
+// Only called for content longer than the chunk size, with `sha` begun as
+// kind 'c'.
+fn chunked(mut sha: Sha1, digests: &[[u8; 20]], chunk_size: u64) -> Sha1 {
+    sha.update(chunk_size.to_le_bytes());
+    for digest in digests {
+        sha.update(digest);
+    }
+    sha
+}
+
+fn record(
+    cx: &Context,
+    state: Option<&State>,
+    absolute: Option<&Path>,
     path: &Path,
-    ignore_unknown_filetypes: bool,
-    metadata: Metadata,
-) -> Result<()> {
-    let sha = begin(path, &metadata, b'd');
-    checksum.put(sha);
+    metadata: &Stat,
+    sha: Sha1,
+) {
+    let digest = sha.finalize().into();
+    if let (Some(state), Some(absolute)) = (state, absolute) {
+        if let Err(error) = state.record(absolute, metadata, digest) {
+            die(state.path(), error);
+        }
+    }
+    cx.put_digest(path, digest);
+}
 
-    for child in path.read_dir()? {
-        let child = child?.path();
-        scope.spawn(move |scope| entry(scope, base, checksum, &child, ignore_unknown_filetypes));
+fn symlink(cx: &Context, path: &Path, at: At, metadata: Stat) -> Result<()> {
+    let mut sha = begin_entry(cx, path, at, &metadata, b'l')?;
+    let target = at.read_link()?;
+    let target = target.as_os_str().as_bytes();
+    sha.update(target);
+    if let Some(cas) = &cx.cas {
+        cas.add_content(path, target, Sha1::digest(target).into())?;
     }
+    cx.put(path, sha);
 
     Ok(())
 }
 
-fn socket(checksum: &Checksum, path: &Path, metadata: Metadata) -> Result<()> {
-    let sha = begin(path, &metadata, b's');
-    checksum.put(sha);
+fn socket(cx: &Context, path: &Path, at: At, metadata: Stat) -> Result<()> {
+    let sha = begin_entry(cx, path, at, &metadata, b's')?;
+    cx.put(path, sha);
 
     Ok(())
 }
 
-fn begin(path: &Path, metadata: &Metadata, kind: u8) -> Sha1 {
+/// Like `walk::hash_entry` but for an entry given by its path within the
+/// root, and without descending into directories.
+fn single_entry(cx: &Context, root: &Dir, path: &Path) -> Result<()> {
+    let at = At {
+        dir: root,
+        name: path,
+    };
+    let metadata = stat::lstat(at, cx.stat_fields)?;
+    if metadata.is_file() {
+        file(cx, path, at, metadata)
+    } else if metadata.is_symlink() {
+        symlink(cx, path, at, metadata)
+    } else if metadata.is_dir() {
+        cx.put_dir(path, begin_entry(cx, path, at, &metadata, b'd')?);
+        Ok(())
+    } else if metadata.is_socket() || metadata.is_door_or_port() && !cx.skip_doors {
+        socket(cx, path, at, metadata)
+    } else if metadata.is_door_or_port() {
+        Ok(())
+    } else {
+        Err("Unsupported file type".into())
+    }
+}
+
+fn begin(path: &Path, mode: u32, kind: u8) -> Sha1 {
     let mut sha = Sha1::new();
     let path_bytes = path.as_os_str().as_bytes();
     sha.update([kind]);
     sha.update((path_bytes.len() as u32).to_le_bytes());
     sha.update(path_bytes);
-    sha.update(metadata.mode().to_le_bytes());
+    sha.update(mode.to_le_bytes());
     sha
 }
 
+// Followed by the file flags and extended attributes, if requested.
+fn begin_entry(cx: &Context, path: &Path, at: At, metadata: &Stat, kind: u8) -> io::Result<Sha1> {
+    let mut sha = begin(path, metadata.mode, kind);
+    if let Some(cas) = &cx.cas {
+        cas.add_entry(path, metadata.mode);
+    }
+    if let Some(flags) = metadata.flags {
+        sha.update(flags.to_le_bytes());
+    }
+    if cx.xattrs {
+        let attrs = xattr::read(at)?;
+        sha.update((attrs.len() as u32).to_le_bytes());
+        for (name, value) in attrs {
+            sha.update((name.len() as u32).to_le_bytes());
+            sha.update(name);
+            sha.update((value.len() as u32).to_le_bytes());
+            sha.update(value);
+        }
+    }
+    Ok(sha)
+}
+
 #[test]
 fn test_cli() {
     <Opt as clap::CommandFactory>::command().debug_assert();
 }
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("0"), Ok(0));
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("512b"), Ok(512));
+    assert_eq!(parse_size("8K"), Ok(8 << 10));
+    assert_eq!(parse_size("8kib"), Ok(8 << 10));
+    assert_eq!(parse_size("100M"), Ok(100 << 20));
+    assert_eq!(parse_size("2GB"), Ok(2 << 30));
+    assert_eq!(parse_size("1T"), Ok(1 << 40));
+    assert!(parse_size("").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("1.5M").is_err());
+    assert!(parse_size("8X").is_err());
+    assert!(parse_size("-1").is_err());
+    assert!(parse_size("16777216T").is_err());
+    assert!(parse_nonzero_size("0K").is_err());
+}
+
+#[test]
+fn test_parse_shard() {
+    let shard = parse_shard("1/4").unwrap();
+    assert_eq!((shard.index, shard.count), (0, 4));
+    let shard = parse_shard("4/4").unwrap();
+    assert_eq!((shard.index, shard.count), (3, 4));
+    for arg in ["0/4", "5/4", "1/0", "1", "a/4", "1/b", "-1/4"] {
+        assert!(parse_shard(arg).is_err(), "{}", arg);
+    }
+
+    // Every path is in exactly one shard.
+    let shards: Vec<Shard> = (1..=3)
+        .map(|k| parse_shard(&format!("{}/3", k)).unwrap())
+        .collect();
+    for i in 0..100 {
+        let path = PathBuf::from(format!("dir/{}", i));
+        let count = shards.iter().filter(|shard| shard.contains(&path)).count();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,227 @@
+//! Listing of the digest of every entry in a directory.
+//!
+//! The first line of each directory's listing is identical to the normal
+//! output, `<checksum>  <DIR>`. It is followed by one `<digest>  <DIR>/<path>`
+//! line per entry in bytewise order of path, and the listing ends with an
+//! empty line, which tells the entries of a DIR apart from the start of the
+//! next DIR when that is a subdirectory of it. The XOR of the entry digests is
+//! the checksum of the directory. With -z, every line ends with a NUL byte
+//! instead of a newline.
+//!
+//! Otherwise a path containing a backslash, newline or carriage return is
+//! written as by GNU sha1sum: those characters are escaped as `\\`, `\n` and
+//! `\r`, and the line begins with a backslash to say so.
+
+use crate::{parse_hex, Hex, Result};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::io::{BufRead, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+pub struct Entry {
+    pub path: PathBuf,
+    pub digest: [u8; 20],
+}
+
+pub struct Manifest {
+    pub label: PathBuf,
+    pub checksum: [u8; 20],
+    pub entries: Vec<Entry>,
+}
+
+pub fn sort(entries: &mut [Entry]) {
+    entries.sort_unstable_by(|a, b| a.path.as_os_str().cmp(b.path.as_os_str()));
+}
+
+pub fn write(out: &mut dyn Write, manifest: &Manifest, eol: u8) -> Result<()> {
+    write_line(out, &manifest.checksum, &manifest.label, eol)?;
+    for entry in &manifest.entries {
+        write_line(out, &entry.digest, &manifest.label.join(&entry.path), eol)?;
+    }
+    out.write_all(&[eol])?;
+    Ok(())
+}
+
+fn write_line(out: &mut dyn Write, digest: &[u8; 20], path: &Path, eol: u8) -> Result<()> {
+    let (prefix, path) = escape(path, eol);
+    out.write_all(prefix)?;
+    write!(out, "{}  ", Hex(digest))?;
+    out.write_all(&path)?;
+    out.write_all(&[eol])?;
+    Ok(())
+}
+
+/// The path as it is written at the end of a line, and what to write at the
+/// start of that line.
+pub fn escape(path: &Path, eol: u8) -> (&'static [u8], Cow<'_, [u8]>) {
+    let path = path.as_os_str().as_bytes();
+    if eol == b'\0' || !path.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r')) {
+        return (b"", Cow::Borrowed(path));
+    }
+    let mut escaped = Vec::with_capacity(path.len() + 2);
+    for &b in path {
+        match b {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            _ => escaped.push(b),
+        }
+    }
+    (b"\\", Cow::Owned(escaped))
+}
+
+fn unescape(path: &[u8]) -> Option<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            unescaped.push(b);
+            continue;
+        }
+        unescaped.push(match bytes.next()? {
+            b'\\' => b'\\',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            _ => return None,
+        });
+    }
+    Some(unescaped)
+}
+
+/// Lines end with `eol`, which is a NUL byte for a manifest written with -z.
+/// A manifest written before listings ended with an empty line is read with
+/// every line under the previous DIR taken to be an entry of it.
+pub fn parse(input: &mut dyn BufRead, eol: u8) -> Result<Vec<Manifest>> {
+    let mut manifests: Vec<Manifest> = Vec::new();
+    let mut ended = true;
+    for (i, line) in input.split(eol).enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            ended = true;
+            continue;
+        }
+        let Some((digest, path)) = parse_line(&line) else {
+            return Err(format!("line {}: expected `<sha1>  <path>`", i + 1).into());
+        };
+        if let Some(manifest) = manifests.last_mut().filter(|_| !ended) {
+            if let Ok(relative) = path.strip_prefix(&manifest.label) {
+                if !relative.as_os_str().is_empty() {
+                    manifest.entries.push(Entry {
+                        path: relative.to_owned(),
+                        digest,
+                    });
+                    continue;
+                }
+            }
+        }
+        ended = false;
+        manifests.push(Manifest {
+            label: path.into_owned(),
+            checksum: digest,
+            entries: Vec::new(),
+        });
+    }
+    for manifest in &mut manifests {
+        sort(&mut manifest.entries);
+    }
+    Ok(manifests)
+}
+
+fn parse_line(line: &[u8]) -> Option<([u8; 20], Cow<'_, Path>)> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let digest = parse_hex(line.get(..40)?)?;
+    let path = line.get(40..)?.strip_prefix(b"  ")?;
+    if path.is_empty() {
+        return None;
+    }
+    let path = if escaped {
+        let path = OsStr::from_bytes(&unescape(path)?).to_owned();
+        Cow::Owned(PathBuf::from(path))
+    } else {
+        Cow::Borrowed(Path::new(OsStr::from_bytes(path)))
+    };
+    Some((digest, path))
+}
+
+#[test]
+fn test_roundtrip() {
+    let entry = |path: &str, digest| Entry {
+        path: PathBuf::from(path),
+        digest: [digest; 20],
+    };
+    let manifests = [
+        Manifest {
+            label: PathBuf::from("a"),
+            checksum: [1; 20],
+            entries: vec![entry("b", 2), entry("b/c\\d", 3), entry("e\nf\r", 4)],
+        },
+        Manifest {
+            label: PathBuf::from("a/b"),
+            checksum: [5; 20],
+            entries: vec![entry("c\\d", 3)],
+        },
+    ];
+
+    let mut out = Vec::new();
+    for manifest in &manifests {
+        write(&mut out, manifest, b'\n').unwrap();
+    }
+    let parsed = parse(&mut out.as_slice(), b'\n').unwrap();
+
+    assert_eq!(parsed.len(), manifests.len());
+    for (parsed, manifest) in parsed.iter().zip(&manifests) {
+        assert_eq!(parsed.label, manifest.label);
+        assert_eq!(parsed.checksum, manifest.checksum);
+        let paths = |m: &Manifest| -> Vec<(PathBuf, [u8; 20])> {
+            m.entries
+                .iter()
+                .map(|e| (e.path.clone(), e.digest))
+                .collect()
+        };
+        assert_eq!(paths(parsed), paths(manifest));
+    }
+}
+
+#[test]
+fn test_escape() {
+    let path = Path::new("a\\b\nc\rd");
+    let (prefix, escaped) = escape(path, b'\n');
+    assert_eq!(prefix, b"\\");
+    assert_eq!(*escaped, *b"a\\\\b\\nc\\rd");
+    assert_eq!(unescape(&escaped).unwrap(), path.as_os_str().as_bytes());
+
+    let (prefix, escaped) = escape(Path::new("plain"), b'\n');
+    assert_eq!((prefix, &*escaped), (&b""[..], &b"plain"[..]));
+
+    // Nothing needs escaping when lines end with a NUL byte.
+    let (prefix, escaped) = escape(path, b'\0');
+    assert_eq!((prefix, &*escaped), (&b""[..], path.as_os_str().as_bytes()));
+
+    assert_eq!(unescape(b"a\\tb"), None);
+    assert_eq!(unescape(b"a\\"), None);
+
+    let line = b"\\da39a3ee5e6b4b0d3255bfef95601890afd80709  dir/a\\\\b\\nc";
+    let (_, parsed) = parse_line(line).unwrap();
+    assert_eq!(parsed, Path::new("dir/a\\b\nc"));
+    let line = b"da39a3ee5e6b4b0d3255bfef95601890afd80709  dir/a\\nb";
+    let (_, parsed) = parse_line(line).unwrap();
+    assert_eq!(parsed, Path::new("dir/a\\nb"));
+}
+
+#[test]
+fn test_parse_nul() {
+    let input = b"0000000000000000000000000000000000000001  dir\0\
+        0000000000000000000000000000000000000002  dir/a\nb\0\
+        0000000000000000000000000000000000000003  dir/\\c\0\0";
+    let manifests = parse(&mut &input[..], b'\0').unwrap();
+    assert_eq!(manifests.len(), 1);
+    let manifest = &manifests[0];
+    assert_eq!(manifest.label, Path::new("dir"));
+    assert_eq!(manifest.checksum[19], 1);
+    let paths: Vec<&Path> = manifest.entries.iter().map(|e| e.path.as_path()).collect();
+    assert_eq!(paths, [Path::new("\\c"), Path::new("a\nb")]);
+}
@@ -0,0 +1,77 @@
+//! Manual page for `sha1dir manpage`.
+//!
+//! The synopsis, options and subcommands come from the same definition as
+//! --help. The sections after them, on the exit status and the format of the
+//! checksum, follow the Behavior section of the readme.
+
+use crate::{die, Opt};
+use clap::CommandFactory;
+use clap_mangen::Man;
+use std::io::{self, Write};
+use std::process;
+
+const EXIT_STATUS: &str = r#".SH "EXIT STATUS"
+.TP
+\fB0\fR
+Every checksum was computed, and nothing differed from what it was compared
+against.
+.TP
+\fB1\fR
+A checksum differed from \fB\-\-expect\fR, or an entry differed in
+\fBverify\fR, \fBdiff\fR or another comparison; or an entry could not be read.
+.TP
+\fB2\fR
+The arguments were not valid.
+"#;
+
+const FORMAT: &str = r".SH FORMAT
+The checksum is the bitwise XOR of one SHA\-1 hash per entry under DIR, not
+counting DIR itself. The hash of each entry is of the kind of entry as one byte,
+4 little endian bytes for the length of its path relative to DIR, the path, 4
+little endian bytes for its mode as given by st_mode, and then:
+.TP
+\fBf\fR
+for a regular file, its contents;
+.TP
+\fBl\fR
+for a symbolic link, the path of its target;
+.TP
+\fBd\fR
+for a directory, nothing more;
+.TP
+\fBs\fR
+for a socket, and on illumos a door or event port, nothing more.
+.PP
+With \fB\-\-chunked\fR \fISIZE\fR, a regular file longer than SIZE bytes has
+kind \fBc\fR, and its mode is followed by 8 little endian bytes for SIZE and
+the SHA\-1 of each consecutive SIZE\-byte chunk of its contents. With
+\fB\-\-file\-flags\fR the mode is followed by 4 little endian bytes for the file
+flags, and with \fB\-\-xattrs\fR by the number of extended attributes and each
+name and value in order of name, every one preceded by its length in 4 little
+endian bytes. With \fB\-\-combine lthash\fR the hashes are summed as LtHash
+lanes rather than combined by XOR.
+.PP
+This is format version 1. A release that changes it does so as a new version,
+and keeps the old ones selectable by \fB\-\-format\-version\fR.
+";
+
+pub fn run() -> ! {
+    let man = Man::new(Opt::command());
+    let mut out = Vec::new();
+    let result = man
+        .render_title(&mut out)
+        .and_then(|()| man.render_name_section(&mut out))
+        .and_then(|()| man.render_synopsis_section(&mut out))
+        .and_then(|()| man.render_description_section(&mut out))
+        .and_then(|()| man.render_options_section(&mut out))
+        .and_then(|()| man.render_subcommands_section(&mut out))
+        .and_then(|()| out.write_all(EXIT_STATUS.as_bytes()))
+        .and_then(|()| out.write_all(FORMAT.as_bytes()))
+        .and_then(|()| man.render_version_section(&mut out))
+        .and_then(|()| man.render_authors_section(&mut out))
+        .and_then(|()| io::stdout().lock().write_all(&out));
+    if let Err(error) = result {
+        die("manpage", error);
+    }
+    process::exit(0);
+}
@@ -0,0 +1,102 @@
+//! Digest of every directory computed from the digests of its children, for
+//! --merkle.
+//!
+//! A directory's digest is the SHA-1 of the byte `'m'`, the directory's own
+//! entry digest (20 zero bytes for the root), and then for each child in
+//! bytewise order of name: 4 little endian bytes for the name length, the
+//! name, and the child's digest. The digest of anything without children,
+//! including an empty directory, is its entry digest. A directory outside of
+//! the --shard has 20 zero bytes for its entry digest, like the root. Two
+//! trees differ below exactly those directories whose digests differ, so a
+//! comparison can descend from the root into only the subtrees that changed.
+//!
+//! The output has the format of --manifest, but with the root's digest in
+//! place of the checksum and a line only for each directory that has
+//! children.
+
+use crate::manifest::{self, Entry, Manifest};
+use crate::Result;
+use sha1::{Digest, Sha1};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+pub fn tree(manifest: &Manifest) -> Manifest {
+    let digests: HashMap<&Path, &[u8; 20]> = manifest
+        .entries
+        .iter()
+        .map(|entry| (entry.path.as_path(), &entry.digest))
+        .collect();
+
+    // Along with every directory above an entry, which with --shard need not
+    // be an entry itself.
+    let mut paths = Vec::new();
+    let mut seen = HashSet::new();
+    for entry in &manifest.entries {
+        for path in entry.path.ancestors() {
+            if path.as_os_str().is_empty() || !seen.insert(path) {
+                break;
+            }
+            paths.push(path);
+        }
+    }
+    paths.sort_unstable_by(|a, b| a.as_os_str().cmp(b.as_os_str()));
+
+    // In order of name. The root has a digest even if it is empty.
+    let mut children: HashMap<&Path, Vec<&Path>> = HashMap::new();
+    children.insert(Path::new(""), Vec::new());
+    for path in paths {
+        let parent = path.parent().unwrap();
+        children.entry(parent).or_default().push(path);
+    }
+
+    // Deepest first, so that every child's digest is known before its
+    // parent's.
+    let mut dirs: Vec<&Path> = children.keys().copied().collect();
+    dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+
+    let mut nodes: HashMap<&Path, [u8; 20]> = HashMap::new();
+    for dir in dirs {
+        let mut sha = Sha1::new();
+        sha.update(b"m");
+        sha.update(digests.get(dir).copied().unwrap_or(&[0; 20]));
+        for child in &children[dir] {
+            let name = child.file_name().unwrap().as_bytes();
+            sha.update((name.len() as u32).to_le_bytes());
+            sha.update(name);
+            let digest = match nodes.get(child) {
+                Some(node) => node,
+                None => digests[child],
+            };
+            sha.update(digest);
+        }
+        nodes.insert(dir, sha.finalize().into());
+    }
+
+    let root = nodes.remove(Path::new("")).unwrap();
+    let mut entries: Vec<Entry> = nodes
+        .into_iter()
+        .map(|(path, digest)| Entry {
+            path: path.to_owned(),
+            digest,
+        })
+        .collect();
+    manifest::sort(&mut entries);
+    Manifest {
+        label: manifest.label.clone(),
+        checksum: root,
+        entries,
+    }
+}
+
+pub fn write(path: &Path, trees: &[Manifest], eol: u8) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for tree in trees {
+        manifest::write(&mut out, tree, eol)?;
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! Nix archive (NAR) serialization, and its SHA-256 in the `sha256:<base32>`
+//! form used for `NarHash` in narinfo files and by `nix-store
+//! --query --hash`.
+//!
+//! Unlike the default checksum, the NAR is a single stream with directory
+//! entries in sorted order, so it is computed by one thread. Nix has no
+//! representation for sockets or device files.
+
+use crate::{die, map, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, Metadata};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+const NIX_BASE32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+pub fn nar_hash(path: &Path, ignore_unknown_filetypes: bool) -> String {
+    let mut nar = Nar {
+        sha: Sha256::new(),
+        ignore_unknown_filetypes,
+    };
+    nar.str(b"nix-archive-1");
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(error) => die(path, error),
+    };
+    if let Err(error) = nar.node(path, &metadata) {
+        die(path, error);
+    }
+    format!("sha256:{}", base32(&nar.sha.finalize()))
+}
+
+struct Nar {
+    sha: Sha256,
+    ignore_unknown_filetypes: bool,
+}
+
+impl Nar {
+    fn str(&mut self, bytes: &[u8]) {
+        self.sha.update((bytes.len() as u64).to_le_bytes());
+        self.sha.update(bytes);
+        let padding = (8 - bytes.len() % 8) % 8;
+        self.sha.update(&[0u8; 8][..padding]);
+    }
+
+    fn node(&mut self, path: &Path, metadata: &Metadata) -> Result<()> {
+        self.str(b"(");
+        let file_type = metadata.file_type();
+        if file_type.is_file() {
+            self.str(b"type");
+            self.str(b"regular");
+            if metadata.permissions().mode() & 0o100 != 0 {
+                self.str(b"executable");
+                self.str(b"");
+            }
+            self.str(b"contents");
+            let mmap = map(path)?;
+            self.str(mmap.as_deref().unwrap_or_default());
+        } else if file_type.is_symlink() {
+            self.str(b"type");
+            self.str(b"symlink");
+            self.str(b"target");
+            self.str(fs::read_link(path)?.as_os_str().as_bytes());
+        } else if file_type.is_dir() {
+            self.str(b"type");
+            self.str(b"directory");
+            let mut children = Vec::new();
+            for child in path.read_dir()? {
+                let child = child?;
+                let metadata = child.metadata()?;
+                let file_type = metadata.file_type();
+                if !(file_type.is_file() || file_type.is_symlink() || file_type.is_dir()) {
+                    if self.ignore_unknown_filetypes {
+                        continue;
+                    }
+                    die(child.path(), "Unsupported file type");
+                }
+                children.push((child.file_name(), metadata));
+            }
+            children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            for (name, metadata) in children {
+                self.str(b"entry");
+                self.str(b"(");
+                self.str(b"name");
+                self.str(name.as_bytes());
+                self.str(b"node");
+                let path = path.join(name);
+                if let Err(error) = self.node(&path, &metadata) {
+                    die(path, error);
+                }
+                self.str(b")");
+            }
+        } else {
+            die(path, "Unsupported file type");
+        }
+        self.str(b")");
+        Ok(())
+    }
+}
+
+// Nix's base32 encodes from the last character backwards, least significant
+// bits of the first byte last.
+fn base32(bytes: &[u8]) -> String {
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    let mut encoded = String::with_capacity(len);
+    for n in (0..len).rev() {
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        let mut c = bytes[i] >> j;
+        if i + 1 < bytes.len() {
+            c |= bytes[i + 1].checked_shl(8 - j as u32).unwrap_or(0);
+        }
+        encoded.push(NIX_BASE32[usize::from(c & 0x1f)] as char);
+    }
+    encoded
+}
+
+#[test]
+fn test_base32() {
+    let empty = Sha256::digest(b"");
+    assert_eq!(
+        base32(&empty),
+        "0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73",
+    );
+}
@@ -0,0 +1,78 @@
+//! Placement of the whole process on one NUMA node, for --numa-node.
+//!
+//! The CPU affinity and memory policy are attributes of each thread that are
+//! inherited by threads it creates, so all threads end up running on the
+//! CPUs of the node and allocating from its memory, including the pages of
+//! file content read into the page cache, if they are set before the thread
+//! pools start. Numbers of threads that default to the number of CPUs then
+//! count only those of the node.
+
+use std::io;
+
+#[cfg(target_os = "linux")]
+pub fn bind(node: usize) -> io::Result<()> {
+    use std::fs;
+    use std::mem;
+    use std::ptr;
+
+    const MPOL_PREFERRED: libc::c_int = 1;
+    const MAX_NODES: usize = 1024;
+    const BITS: usize = mem::size_of::<libc::c_ulong>() * 8;
+
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let cpulist = match fs::read_to_string(path) {
+        Ok(cpulist) => cpulist,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let message = format!("no NUMA node {}", node);
+            return Err(io::Error::new(io::ErrorKind::NotFound, message));
+        }
+        Err(error) => return Err(error),
+    };
+
+    let mut cpus = unsafe { mem::zeroed::<libc::cpu_set_t>() };
+    for cpu in parse_cpulist(&cpulist)? {
+        unsafe { libc::CPU_SET(cpu, &mut cpus) };
+    }
+    if unsafe { libc::sched_setaffinity(0, mem::size_of_val(&cpus), ptr::addr_of!(cpus)) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut nodemask = [0 as libc::c_ulong; MAX_NODES / BITS];
+    nodemask[node / BITS] |= 1 << (node % BITS);
+    // The kernel takes one less than the number of bits given.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            MPOL_PREFERRED,
+            nodemask.as_ptr(),
+            MAX_NODES + 1,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind(_node: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "NUMA placement is only supported on Linux",
+    ))
+}
+
+// Such as "0-3,8-11".
+#[cfg(target_os = "linux")]
+fn parse_cpulist(cpulist: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid cpulist");
+    let mut cpus = Vec::new();
+    for range in cpulist.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first: usize = first.parse().map_err(|_| invalid())?;
+        let last: usize = last.parse().map_err(|_| invalid())?;
+        cpus.extend(first..=last);
+    }
+    Ok(cpus)
+}
@@ -0,0 +1,86 @@
+//! Order in which the files on each device are hashed, for --order.
+//!
+//! Without it, files are hashed roughly in the order that directory listing
+//! finds them, which on a spinning disk means seeking back and forth between
+//! unrelated parts of the disk, and may leave the largest file until last.
+//! Sorting requires that the whole tree be listed before hashing starts.
+
+use crate::dirfd::At;
+use crate::stat::Stat;
+use clap::ValueEnum;
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum Order {
+    /// By the location of each file's first extent on disk where the
+    /// filesystem reports it, otherwise by inode number
+    Physical,
+    /// Largest files first, so that a huge file found late does not leave
+    /// the other threads idle at the end while it is hashed
+    Largest,
+}
+
+/// Files are hashed in increasing order of key.
+pub fn key(order: Order, at: At, stat: &Stat) -> u64 {
+    match order {
+        Order::Physical => {
+            if stat.is_file() {
+                if let Some(physical) = physical_offset(at) {
+                    return physical;
+                }
+            }
+            stat.ino
+        }
+        Order::Largest => u64::MAX - stat.size,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn physical_offset(at: At) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; 1],
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    let file = at.open(0).ok()?;
+    let mut fiemap = Fiemap {
+        start: 0,
+        length: u64::MAX,
+        flags: 0,
+        mapped_extents: 0,
+        extent_count: 1,
+        reserved: 0,
+        extents: [FiemapExtent::default()],
+    };
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) };
+    if ret != 0 || fiemap.mapped_extents == 0 {
+        return None;
+    }
+    Some(fiemap.extents[0].physical)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn physical_offset(_at: At) -> Option<u64> {
+    None
+}
@@ -0,0 +1,97 @@
+//! CPU and I/O scheduling priority of the whole process.
+//!
+//! On Linux both are attributes of each thread that are inherited by threads
+//! it creates, so they must be set before the thread pool starts.
+
+use std::io;
+
+// Parsed on every platform so that --ionice is rejected the same way, but
+// only applied on Linux.
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct IoPriority {
+    class: IoClass,
+    level: u8,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum IoClass {
+    Realtime = 1,
+    BestEffort = 2,
+    Idle = 3,
+}
+
+pub fn set_nice(nice: i32) -> io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_ionice(priority: IoPriority) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    let ioprio =
+        (priority.class as libc::c_int) << IOPRIO_CLASS_SHIFT | libc::c_int::from(priority.level);
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_ionice(_priority: IoPriority) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "I/O scheduling classes are only supported on Linux",
+    ))
+}
+
+/// Same syntax as `ionice -c CLASS -n LEVEL`, with the class by name or
+/// number.
+pub fn parse_ionice(arg: &str) -> Result<IoPriority, String> {
+    let (class, level) = match arg.split_once(':') {
+        Some((class, level)) => (class, Some(level)),
+        None => (arg, None),
+    };
+    let class = match class {
+        "1" | "realtime" => IoClass::Realtime,
+        "2" | "best-effort" => IoClass::BestEffort,
+        "3" | "idle" => IoClass::Idle,
+        _ => return Err(format!("unknown I/O scheduling class: {}", class)),
+    };
+    let level = match (class, level) {
+        (IoClass::Idle, Some(_)) => return Err("the idle class has no level".to_owned()),
+        (_, None) => 4,
+        (_, Some(level)) => match level.parse() {
+            Ok(level @ 0..=7) => level,
+            _ => return Err("level must be between 0 and 7".to_owned()),
+        },
+    };
+    Ok(IoPriority { class, level })
+}
+
+#[test]
+fn test_parse_ionice() {
+    let parse = |arg| parse_ionice(arg).map(|p| (p.class as i32, p.level));
+    assert_eq!(parse("idle"), Ok((3, 4)));
+    assert_eq!(parse("3"), Ok((3, 4)));
+    assert_eq!(parse("best-effort"), Ok((2, 4)));
+    assert_eq!(parse("2:7"), Ok((2, 7)));
+    assert_eq!(parse("realtime:0"), Ok((1, 0)));
+    for arg in [
+        "0",
+        "4",
+        "none",
+        "idle:0",
+        "2:8",
+        "2:-1",
+        "2:",
+        "best-effort:low",
+    ] {
+        assert!(parse_ionice(arg).is_err(), "{}", arg);
+    }
+}
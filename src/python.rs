@@ -0,0 +1,138 @@
+//! Python module `sha1dir`, for the `python` feature.
+//!
+//! Built as an extension module by:
+//!
+//! ```console
+//! $ cargo rustc --release --lib --features python,pyo3/extension-module --crate-type cdylib
+//! $ cp target/release/libsha1dir.so sha1dir.so
+//! ```
+//!
+//! ```python
+//! import sha1dir
+//! print(sha1dir.hash("/srv/data", threads=4))
+//! for path, kind, digest, size in sha1dir.entries("/srv/data"):
+//!     print(digest, kind, size, path)
+//! ```
+
+// Doc comments here are the Python docstrings.
+#![allow(clippy::doc_markdown)]
+
+use crate::{Checksummer, EntryKind, Error, Errors, Options};
+use pyo3::exceptions::{PyOSError, PyStopIteration};
+use pyo3::prelude::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+#[pymodule(name = "sha1dir")]
+fn init(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(hash, m)?)?;
+    m.add_function(wrap_pyfunction!(entries, m)?)?;
+    m.add_class::<Entries>()?;
+    Ok(())
+}
+
+/// Checksum of the directory at path as 40 hex digits, the same as printed
+/// by the sha1dir command. Raises OSError if any entry could not be hashed,
+/// once the rest of the tree is hashed.
+#[pyfunction]
+#[pyo3(signature = (path, *, threads=None, ignore_unknown_filetypes=false))]
+fn hash(
+    py: Python,
+    path: PathBuf,
+    threads: Option<usize>,
+    ignore_unknown_filetypes: bool,
+) -> PyResult<String> {
+    let checksummer = checksummer(threads, ignore_unknown_filetypes)?;
+    match py.detach(|| checksummer.checksum(path)) {
+        Ok(checksum) => Ok(checksum.to_string()),
+        Err(errors) => Err(errors_to_py(&errors)),
+    }
+}
+
+/// Iterator over a (path, kind, digest, size) tuple for each entry of the
+/// directory at path, in no particular order, as soon as it is hashed. The
+/// kind is "file", "dir", "symlink" or "socket", and size is the number of
+/// bytes of content of a file. An entry that could not be hashed raises
+/// OSError from next(), after which iteration may continue.
+#[pyfunction]
+#[pyo3(signature = (path, *, threads=None, ignore_unknown_filetypes=false))]
+fn entries(
+    path: PathBuf,
+    threads: Option<usize>,
+    ignore_unknown_filetypes: bool,
+) -> PyResult<Entries> {
+    let checksummer = checksummer(threads, ignore_unknown_filetypes)?;
+    Ok(Entries {
+        entries: Mutex::new(checksummer.entries(path)),
+    })
+}
+
+#[pyclass(module = "sha1dir")]
+struct Entries {
+    entries: Mutex<crate::Entries>,
+}
+
+#[pymethods]
+impl Entries {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python) -> PyResult<(PathBuf, &'static str, String, u64)> {
+        let next = py.detach(|| self.entries.lock().unwrap().next());
+        match next {
+            Some(Ok(entry)) => {
+                let kind = match entry.kind() {
+                    EntryKind::File => "file",
+                    EntryKind::Dir => "dir",
+                    EntryKind::Symlink => "symlink",
+                    EntryKind::Socket => "socket",
+                    EntryKind::Fifo => "fifo",
+                    EntryKind::Device => "device",
+                    EntryKind::Unknown => "unknown",
+                };
+                let digest = entry.digest().to_string();
+                Ok((entry.path, kind, digest, entry.size))
+            }
+            Some(Err(error)) => Err(error_to_py(&error)),
+            None => Err(PyStopIteration::new_err(())),
+        }
+    }
+}
+
+fn checksummer(threads: Option<usize>, ignore_unknown_filetypes: bool) -> PyResult<Checksummer> {
+    let mut options = Options::new();
+    if let Some(threads) = threads {
+        options.threads(threads);
+    }
+    options.ignore_unknown_filetypes(ignore_unknown_filetypes);
+    Ok(options.build()?)
+}
+
+fn errors_to_py(errors: &Errors) -> PyErr {
+    if errors.len() == 1 {
+        return error_to_py(errors.iter().next().unwrap());
+    }
+    let messages: Vec<String> = errors.iter().map(Error::to_string).collect();
+    PyOSError::new_err(messages.join("\n"))
+}
+
+// Constructed with errno, the way Python picks the subclass of OSError such
+// as FileNotFoundError.
+fn error_to_py(error: &Error) -> PyErr {
+    let path = error.path().to_owned();
+    match error {
+        Error::Io { error, .. } | Error::WalkInterrupted { error, .. } => {
+            if let Some(errno) = error.raw_os_error() {
+                let message = error.to_string();
+                let suffix = format!(" (os error {})", errno);
+                let strerror = message.strip_suffix(&suffix).unwrap_or(&message);
+                return PyOSError::new_err((errno, strerror.to_owned(), path));
+            }
+        }
+        Error::UnsupportedFileType { .. }
+        | Error::NotADirectory { .. }
+        | Error::Cancelled { .. } => {}
+    }
+    PyOSError::new_err(error.to_string())
+}
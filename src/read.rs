@@ -0,0 +1,85 @@
+//! Reading of file content into a reused buffer, as an alternative to mapping
+//! it, for --no-mmap, --mmap-threshold and --direct-io.
+//!
+//! With `O_DIRECT` the kernel requires the buffer, offset and length of reads
+//! to be aligned to the logical block size of the device, which for all
+//! common devices divides 4096, so the buffer is always aligned that way.
+//! Filesystems that do not support `O_DIRECT`, such as tmpfs, are read
+//! through the page cache instead.
+
+use crate::dirfd::At;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Read};
+
+const ALIGN: usize = 4096;
+const BUFFER_LEN: usize = 1 << 20;
+
+thread_local! {
+    static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Passes the content to `f` one buffer at a time and returns its length.
+pub fn buffered(mut file: File, f: &mut dyn FnMut(&[u8])) -> io::Result<u64> {
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        if buffer.is_empty() {
+            buffer.resize(BUFFER_LEN + ALIGN, 0);
+        }
+        let offset = buffer.as_ptr().align_offset(ALIGN);
+        let buffer = &mut buffer[offset..offset + BUFFER_LEN];
+
+        let mut len = 0;
+        loop {
+            match file.read(buffer) {
+                Ok(0) => return Ok(len),
+                Ok(n) => {
+                    f(&buffer[..n]);
+                    len += n as u64;
+                }
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => return Err(error),
+            }
+        }
+    })
+}
+
+/// Like `buffered` but bypassing the page cache.
+pub fn direct(at: At, noatime: bool, f: &mut dyn FnMut(&[u8])) -> io::Result<u64> {
+    buffered(open_direct(at, noatime)?, f)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn open_direct(at: At, noatime: bool) -> io::Result<File> {
+    match at.open(libc::O_DIRECT) {
+        Err(error) if error.raw_os_error() == Some(libc::EINVAL) => crate::open(at, noatime),
+        Ok(file) if noatime => {
+            set_noatime(&file);
+            Ok(file)
+        }
+        result => result,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn open_direct(at: At, noatime: bool) -> io::Result<File> {
+    crate::open(at, noatime)
+}
+
+// Added after opening so that a file not owned by the user is still read
+// directly, just with its access time updated.
+#[cfg(target_os = "linux")]
+fn set_noatime(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    unsafe {
+        let fd = file.as_raw_fd();
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags != -1 {
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NOATIME);
+        }
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "freebsd"))]
+fn set_noatime(_file: &File) {}
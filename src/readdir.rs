@@ -0,0 +1,196 @@
+//! Listing of the names in a directory.
+//!
+//! On Linux the entries are read with `getdents64` straight into one large
+//! buffer, and each name is handed out borrowed from it, without allocating.
+//! The type of an entry that the filesystem records in the listing is passed
+//! on too, which saves a stat for directories. Elsewhere this is `readdir`.
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::OwnedFd;
+
+pub struct DirEntry<'a> {
+    pub name: &'a OsStr,
+    is_dir: bool,
+}
+
+impl DirEntry<'_> {
+    /// Known to be a directory from the listing alone. Otherwise, it may be
+    /// anything.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+}
+
+// Each directory is listed once, so the offset of its own descriptor is
+// used.
+#[cfg(target_os = "linux")]
+pub struct ReadDir<'a> {
+    fd: &'a OwnedFd,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+}
+
+#[cfg(target_os = "linux")]
+const BUFFER_LEN: usize = 64 << 10;
+
+#[cfg(target_os = "linux")]
+impl<'a> ReadDir<'a> {
+    pub fn new(fd: &'a OwnedFd) -> io::Result<Self> {
+        Ok(ReadDir {
+            fd,
+            buf: vec![0; BUFFER_LEN],
+            pos: 0,
+            end: 0,
+        })
+    }
+
+    /// Skips `.` and `..`.
+    pub fn next_entry(&mut self) -> Option<io::Result<DirEntry<'_>>> {
+        use std::os::unix::io::AsRawFd;
+
+        // struct linux_dirent64 {
+        //     ino64_t d_ino;
+        //     off64_t d_off;
+        //     unsigned short d_reclen;
+        //     unsigned char d_type;
+        //     char d_name[];
+        // };
+        const RECLEN: usize = 16;
+        const TYPE: usize = 18;
+        const NAME: usize = 19;
+
+        let (start, name_len, is_dir) = loop {
+            if self.pos == self.end {
+                let len = unsafe {
+                    libc::syscall(
+                        libc::SYS_getdents64,
+                        self.fd.as_raw_fd(),
+                        self.buf.as_mut_ptr(),
+                        self.buf.len(),
+                    )
+                };
+                let Ok(len) = usize::try_from(len) else {
+                    return Some(Err(io::Error::last_os_error()));
+                };
+                if len == 0 {
+                    return None;
+                }
+                self.pos = 0;
+                self.end = len;
+            }
+
+            let record = &self.buf[self.pos..self.end];
+            let reclen = usize::from(u16::from_ne_bytes([record[RECLEN], record[RECLEN + 1]]));
+            let is_dir = record[TYPE] == libc::DT_DIR;
+            let name = &record[NAME..reclen];
+            let name_len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let start = self.pos + NAME;
+            self.pos += reclen;
+
+            let name = &name[..name_len];
+            if name != b"." && name != b".." {
+                break (start, name_len, is_dir);
+            }
+        };
+
+        let name = OsStr::from_bytes(&self.buf[start..start + name_len]);
+        Some(Ok(DirEntry { name, is_dir }))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct ReadDir<'a> {
+    stream: *mut libc::DIR,
+    _fd: std::marker::PhantomData<&'a OwnedFd>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl<'a> ReadDir<'a> {
+    pub fn new(fd: &'a OwnedFd) -> io::Result<Self> {
+        use std::os::unix::io::IntoRawFd;
+
+        // The stream takes ownership of the descriptor it is given.
+        let fd = fd.try_clone()?.into_raw_fd();
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let error = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(error);
+        }
+        Ok(ReadDir {
+            stream,
+            _fd: std::marker::PhantomData,
+        })
+    }
+
+    /// Skips `.` and `..`.
+    pub fn next_entry(&mut self) -> Option<io::Result<DirEntry<'_>>> {
+        use std::ffi::CStr;
+
+        loop {
+            clear_errno();
+            let entry = unsafe { libc::readdir(self.stream) };
+            if entry.is_null() {
+                // The end of the directory leaves errno unchanged.
+                return take_errno().map(Err);
+            }
+            let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+            let name = name.to_bytes();
+            if name != b"." && name != b".." {
+                let name = OsStr::from_bytes(name);
+                return Some(Ok(DirEntry {
+                    name,
+                    is_dir: false,
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Drop for ReadDir<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::closedir(self.stream) };
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn errno() -> *mut libc::c_int {
+    unsafe { libc::__error() }
+}
+
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+fn errno() -> *mut libc::c_int {
+    unsafe { libc::__errno() }
+}
+
+#[cfg(any(target_os = "illumos", target_os = "solaris"))]
+fn errno() -> *mut libc::c_int {
+    unsafe { libc::___errno() }
+}
+
+#[cfg(target_os = "dragonfly")]
+fn errno() -> *mut libc::c_int {
+    unsafe { libc::__errno_location() }
+}
+
+#[cfg(target_os = "haiku")]
+fn errno() -> *mut libc::c_int {
+    unsafe { libc::_errnop() }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clear_errno() {
+    unsafe { *errno() = 0 };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn take_errno() -> Option<io::Error> {
+    match unsafe { *errno() } {
+        0 => None,
+        errno => Some(io::Error::from_raw_os_error(errno)),
+    }
+}
@@ -0,0 +1,93 @@
+//! Comparison of a local directory against one on another host, for `sha1dir
+//! remote-diff`.
+//!
+//! The remote directory is hashed by the sha1dir on that host over ssh, with
+//! every option that changes which entries are hashed or their digests passed
+//! along, and only its manifest comes back over the connection.
+
+use crate::{
+    canonicalize, die, diff, manifest, manifest_of, open_audit_log, Combine, Opt, RemoteDiff,
+};
+use clap::ValueEnum;
+use std::io::BufReader;
+use std::process::{Command, Stdio};
+
+pub fn run(opt: &Opt, args: &RemoteDiff) -> ! {
+    let Some((host, path)) = args.remote.split_once(':') else {
+        die(&args.remote, "expected [USER@]HOST:PATH");
+    };
+
+    let mut remote_command = vec![quote(&args.sha1dir_path), "--manifest".to_owned()];
+    if opt.ignore_unknown_filetypes {
+        remote_command.push("--ignore-unknown-filetypes".to_owned());
+    }
+    if opt.zero {
+        remote_command.push("-z".to_owned());
+    }
+    if opt.xattrs {
+        remote_command.push("--xattrs".to_owned());
+    }
+    if opt.file_flags {
+        remote_command.push("--file-flags".to_owned());
+    }
+    if opt.skip_doors {
+        remote_command.push("--skip-doors".to_owned());
+    }
+    if let Some(shard) = opt.shard {
+        remote_command.push(format!("--shard={}/{}", shard.index + 1, shard.count));
+    }
+    if let Some(size) = opt.chunked {
+        remote_command.push(format!("--chunked={}", size));
+    }
+    if opt.combine != Combine::Xor {
+        let combine = opt.combine.to_possible_value().unwrap();
+        remote_command.push(format!("--combine={}", combine.get_name()));
+    }
+    // Even the default, which a remote sha1dir of a later release may not
+    // share.
+    remote_command.push(format!("--format-version={}", opt.format_version));
+    remote_command.push("--".to_owned());
+    remote_command.push(quote(path));
+
+    // The remote side hashes concurrently with the local side; its manifest
+    // is only read once the local one is complete.
+    let mut child = match Command::new("ssh")
+        .arg("--")
+        .arg(host)
+        .arg(remote_command.join(" "))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(error) => die("ssh", error),
+    };
+
+    let audit_log = open_audit_log(opt);
+    let local = manifest_of(
+        opt,
+        &args.local,
+        &canonicalize(&args.local),
+        audit_log.as_ref(),
+    );
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let remote = match manifest::parse(&mut stdout, opt.eol()) {
+        Ok(mut manifests) if manifests.len() == 1 => manifests.remove(0),
+        Ok(_) => die(&args.remote, "unexpected output from remote sha1dir"),
+        Err(error) => die(&args.remote, error),
+    };
+    match child.wait() {
+        Ok(status) if status.success() => {}
+        Ok(status) => die(&args.remote, format_args!("remote sha1dir {}", status)),
+        Err(error) => die("ssh", error),
+    }
+
+    diff::report(&remote, &local, &args.report, opt.style());
+}
+
+// Arguments are joined into a single command line that ssh hands to the
+// remote user's shell.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
@@ -0,0 +1,48 @@
+//! Limit on the number of file descriptors that the process may have open.
+//!
+//! The limit is raised at startup, before the walk decides how many
+//! directories it may hold open.
+
+use std::mem::MaybeUninit;
+use std::ptr;
+
+// From <sys/syslimits.h>, which the libc crate does not declare.
+#[cfg(target_os = "macos")]
+const OPEN_MAX: libc::rlim_t = 10240;
+
+/// The soft limit, or `None` if there is none.
+pub fn nofile() -> Option<libc::rlim_t> {
+    let rlimit = get()?;
+    if rlimit.rlim_cur == libc::RLIM_INFINITY {
+        None
+    } else {
+        Some(rlimit.rlim_cur)
+    }
+}
+
+/// Raises the soft limit as far as the hard limit allows. On failure the
+/// limit is left as it was.
+pub fn raise_nofile() {
+    let Some(mut rlimit) = get() else {
+        return;
+    };
+    if rlimit.rlim_cur == rlimit.rlim_max {
+        return;
+    }
+    rlimit.rlim_cur = rlimit.rlim_max;
+    // An unlimited hard limit is not accepted as the soft limit on macOS,
+    // which caps it at OPEN_MAX.
+    #[cfg(target_os = "macos")]
+    if rlimit.rlim_cur == libc::RLIM_INFINITY {
+        rlimit.rlim_cur = OPEN_MAX;
+    }
+    unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, ptr::addr_of!(rlimit)) };
+}
+
+fn get() -> Option<libc::rlimit> {
+    let mut rlimit = MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, rlimit.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    Some(unsafe { rlimit.assume_init() })
+}
@@ -0,0 +1,40 @@
+use crate::Checksum;
+use serde::de::{Deserialize, Deserializer, Error, Unexpected, Visitor};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+
+impl Serialize for Checksum {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Checksum {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ChecksumVisitor;
+
+        impl Visitor<'_> for ChecksumVisitor {
+            type Value = Checksum;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("40 hex digits")
+            }
+
+            fn visit_str<E>(self, hex: &str) -> Result<Self::Value, E>
+            where
+                E: Error,
+            {
+                hex.parse()
+                    .map_err(|_| E::invalid_value(Unexpected::Str(hex), &self))
+            }
+        }
+
+        deserializer.deserialize_str(ChecksumVisitor)
+    }
+}
@@ -0,0 +1,92 @@
+//! Minisign signature appended to the output, so that a stored manifest
+//! cannot be edited to match a corrupted copy without detection.
+//!
+//! The signature covers every byte of output before it. It is the same
+//! four-line signature that `minisign -S` writes to a `.minisig` file, so the
+//! output can also be separated and checked with `minisign -V`.
+
+use crate::{gpg, Result};
+use minisign::{PublicKey, SecretKey, SecretKeyBox, SignatureBox};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::str;
+
+pub fn load_secret_key(path: &Path) -> Result<SecretKey> {
+    let key = fs::read_to_string(path)?;
+
+    // Keys created with `minisign -G -W` have no password.
+    if let Ok(key) = SecretKeyBox::from_string(&key)?.into_secret_key(Some(String::new())) {
+        return Ok(key);
+    }
+
+    // Prompting on the terminal rather than stdout, which carries the output
+    // being signed.
+    let password = rpassword::prompt_password("Password: ")?;
+    Ok(SecretKeyBox::from_string(&key)?.into_secret_key(Some(password))?)
+}
+
+pub fn sign(key: &SecretKey, output: &[u8]) -> Result<String> {
+    let signature = minisign::sign(None, key, output, None, None)?;
+    Ok(signature.into_string())
+}
+
+/// Separates output written with --sign or --gpg-sign into the signed part
+/// and the signature, if there is one. The signature follows the final
+/// record, which with -z ends in NUL rather than newline.
+pub fn split_signature(content: &[u8], eol: u8) -> (&[u8], Option<&[u8]>) {
+    let mut offset = 0;
+    for line in content.split_inclusive(|&b| b == eol) {
+        if line.starts_with(b"untrusted comment: ") || line.starts_with(gpg::ARMOR_HEADER) {
+            return (&content[..offset], Some(&content[offset..]));
+        }
+        offset += line.len();
+    }
+    (content, None)
+}
+
+pub fn verify(public_key: &Path, signed: &[u8], signature: &[u8]) -> Result<()> {
+    let public_key = PublicKey::from_file(public_key)?;
+    let signature = SignatureBox::from_string(str::from_utf8(signature)?)?;
+    let quiet = true;
+    let output = false;
+    let allow_legacy = false;
+    minisign::verify(
+        &public_key,
+        &signature,
+        Cursor::new(signed),
+        quiet,
+        output,
+        allow_legacy,
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_split_signature() {
+    let keypair = minisign::KeyPair::generate_unencrypted_keypair().unwrap();
+    let public_key = std::env::temp_dir().join(format!("sha1dir-test-{}.pub", std::process::id()));
+    fs::write(&public_key, keypair.pk.to_box().unwrap().to_string()).unwrap();
+
+    for (eol, listing) in [
+        (
+            b'\n',
+            &b"da39a3ee5e6b4b0d3255bfef95601890afd80709  dir\n\n"[..],
+        ),
+        (
+            b'\0',
+            &b"da39a3ee5e6b4b0d3255bfef95601890afd80709  dir\0\0"[..],
+        ),
+    ] {
+        assert_eq!(split_signature(listing, eol), (listing, None));
+
+        let signature = sign(&keypair.sk, listing).unwrap();
+        let content = [listing, signature.as_bytes()].concat();
+        let (signed, split) = split_signature(&content, eol);
+        assert_eq!(signed, listing);
+        assert_eq!(split, Some(signature.as_bytes()));
+        verify(&public_key, signed, split.unwrap()).unwrap();
+    }
+
+    let _ = fs::remove_file(&public_key);
+}
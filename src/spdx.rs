@@ -0,0 +1,89 @@
+//! Per-file section of an SPDX document in the tag-value format, with the
+//! SHA-1 and SHA-256 of each regular file's content.
+//!
+//! Only regular files are listed, by path relative to the directory with a
+//! leading `./` as SPDX expects. The package and document information that
+//! goes around these sections is left to whatever assembles the SBOM. Since
+//! a `FileName` cannot go on past the end of its line, a path containing a
+//! backslash, newline or carriage return is escaped as in a manifest, though
+//! without the backslash that begins such a line there.
+
+use crate::manifest;
+use crate::{Hex, Result};
+use parking_lot::Mutex;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub struct SpdxFiles {
+    files: Mutex<Vec<File>>,
+}
+
+struct File {
+    path: PathBuf,
+    sha1: [u8; 20],
+    sha256: [u8; 32],
+}
+
+/// For content that is read a piece at a time.
+pub struct Checksums {
+    sha1: Sha1,
+    sha256: Sha256,
+}
+
+impl Checksums {
+    pub fn new() -> Self {
+        Checksums {
+            sha1: Sha1::new(),
+            sha256: Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.sha1.update(bytes);
+        self.sha256.update(bytes);
+    }
+}
+
+impl SpdxFiles {
+    pub fn new() -> Self {
+        SpdxFiles {
+            files: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add(&self, path: &Path, content: &[u8]) {
+        let mut checksums = Checksums::new();
+        checksums.update(content);
+        self.finish(path, checksums);
+    }
+
+    pub fn finish(&self, path: &Path, checksums: Checksums) {
+        let file = File {
+            path: path.to_owned(),
+            sha1: checksums.sha1.finalize().into(),
+            sha256: checksums.sha256.finalize().into(),
+        };
+        self.files.lock().push(file);
+    }
+
+    /// Identifiers are numbered starting from `next_id`, which is advanced so
+    /// that the sections of several directories can be concatenated.
+    pub fn write(self, out: &mut dyn Write, next_id: &mut usize) -> Result<()> {
+        let mut files = self.files.into_inner();
+        files.sort_unstable_by(|a, b| a.path.as_os_str().cmp(b.path.as_os_str()));
+        for File { path, sha1, sha256 } in files {
+            let (_, path) = manifest::escape(&path, b'\n');
+            out.write_all(b"FileName: ./")?;
+            out.write_all(&path)?;
+            writeln!(out)?;
+            writeln!(out, "SPDXID: SPDXRef-File-{}", next_id)?;
+            writeln!(out, "FileChecksum: SHA1: {}", Hex(&sha1))?;
+            writeln!(out, "FileChecksum: SHA256: {}", Hex(&sha256))?;
+            writeln!(out)?;
+            *next_id += 1;
+        }
+        Ok(())
+    }
+}
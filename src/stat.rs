@@ -0,0 +1,164 @@
+//! File metadata as needed for hashing, without following symlinks.
+//!
+//! On Linux this comes from `statx` with a mask of only the fields that are
+//! used, which lets network filesystems such as Lustre and GPFS skip fetching
+//! the rest from their servers. The change time is only requested by callers
+//! that need it, as are the BSD file flags. Elsewhere, or on kernels or in
+//! sandboxes without `statx`, it comes from `fstatat`.
+
+use crate::archive::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
+use crate::dirfd::{self, At};
+use std::io;
+use std::mem::MaybeUninit;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Stat {
+    pub mode: u32,
+    pub size: u64,
+    pub ino: u64,
+    pub dev: u64,
+    /// Seconds and nanoseconds, if requested.
+    pub ctime: Option<(i64, i64)>,
+    /// BSD file flags as given by `st_flags`, if requested.
+    pub flags: Option<u32>,
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct Fields {
+    pub ctime: bool,
+    pub flags: bool,
+}
+
+// Doors and event ports, which only illumos and Solaris have. Other systems
+// may give the same bits other meanings, such as whiteouts on the BSDs.
+const S_IFDOOR: u32 = 0o150_000;
+const S_IFPORT: u32 = 0o160_000;
+
+pub const FLAGS_SUPPORTED: bool = cfg!(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+));
+
+impl Stat {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub fn is_socket(&self) -> bool {
+        self.mode & S_IFMT == S_IFSOCK
+    }
+
+    pub fn is_door_or_port(&self) -> bool {
+        cfg!(any(target_os = "illumos", target_os = "solaris"))
+            && matches!(self.mode & S_IFMT, S_IFDOOR | S_IFPORT)
+    }
+
+    // The types of these fields differ between platforms.
+    #[allow(
+        clippy::cast_possible_wrap,
+        clippy::cast_sign_loss,
+        clippy::unnecessary_cast,
+        clippy::useless_conversion
+    )]
+    pub fn from_stat(stat: &libc::stat, fields: Fields) -> Self {
+        Stat {
+            mode: u32::from(stat.st_mode),
+            size: stat.st_size as u64,
+            ino: stat.st_ino as u64,
+            dev: stat.st_dev as u64,
+            ctime: if fields.ctime {
+                Some((stat.st_ctime as i64, stat.st_ctime_nsec as i64))
+            } else {
+                None
+            },
+            flags: if fields.flags { flags(stat) } else { None },
+        }
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+fn flags(stat: &libc::stat) -> Option<u32> {
+    Some(stat.st_flags)
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "freebsd")))]
+fn flags(_stat: &libc::stat) -> Option<u32> {
+    None
+}
+
+fn fstatat(at: At, fields: Fields) -> io::Result<Stat> {
+    let name = dirfd::c_string(at.name)?;
+    let mut buf = MaybeUninit::<libc::stat>::uninit();
+    let flags = libc::AT_SYMLINK_NOFOLLOW;
+    if unsafe { libc::fstatat(at.dir.raw_fd(), name.as_ptr(), buf.as_mut_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Stat::from_stat(unsafe { buf.assume_init_ref() }, fields))
+}
+
+#[cfg(all(target_os = "linux", any(target_env = "gnu", target_env = "musl")))]
+pub fn lstat(at: At, fields: Fields) -> io::Result<Stat> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+    if UNSUPPORTED.load(Ordering::Relaxed) {
+        return fstatat(at, fields);
+    }
+
+    let mut mask = libc::STATX_TYPE | libc::STATX_MODE | libc::STATX_SIZE | libc::STATX_INO;
+    if fields.ctime {
+        mask |= libc::STATX_CTIME;
+    }
+
+    let name = dirfd::c_string(at.name)?;
+    let mut buf = MaybeUninit::<libc::statx>::uninit();
+    let ret = unsafe {
+        libc::statx(
+            at.dir.raw_fd(),
+            name.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            mask,
+            buf.as_mut_ptr(),
+        )
+    };
+    if ret != 0 {
+        let error = io::Error::last_os_error();
+        // Seccomp filters that predate statx, such as Docker's default
+        // profile on older hosts, fail it with EPERM rather than ENOSYS.
+        if matches!(error.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) {
+            UNSUPPORTED.store(true, Ordering::Relaxed);
+            return lstat(at, fields);
+        }
+        return Err(error);
+    }
+
+    let buf = unsafe { buf.assume_init() };
+    let time = |timestamp: libc::statx_timestamp| (timestamp.tv_sec, i64::from(timestamp.tv_nsec));
+    Ok(Stat {
+        mode: u32::from(buf.stx_mode),
+        size: buf.stx_size,
+        ino: buf.stx_ino,
+        dev: libc::makedev(buf.stx_dev_major, buf.stx_dev_minor),
+        ctime: if fields.ctime {
+            Some(time(buf.stx_ctime))
+        } else {
+            None
+        },
+        flags: None,
+    })
+}
+
+#[cfg(not(all(target_os = "linux", any(target_env = "gnu", target_env = "musl"))))]
+pub fn lstat(at: At, fields: Fields) -> io::Result<Stat> {
+    fstatat(at, fields)
+}
@@ -0,0 +1,226 @@
+//! Checkpoint file recording the digest of every regular file hashed so far.
+//!
+//! The file begins with a header naming what the digests depend on besides
+//! the files themselves: the options that change how an entry is hashed, and
+//! the absolute path of every DIR, since each digest covers the path relative
+//! to its DIR. Resuming with a header that differs is refused rather than
+//! reusing digests that would make a wrong checksum.
+//!
+//! After it, each record is `<digest> <ino> <size> <ctime>.<nsec> <path>` terminated by
+//! a NUL byte, where path is absolute. A record is only reused on resume if
+//! the inode, size and ctime still match, so a file modified in between runs
+//! is hashed again. Records are appended as files finish and flushed at most
+//! once per second; a record cut short by a crash is discarded on resume.
+
+use crate::stat::Stat;
+use crate::{parse_hex, Result};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{Duration, Instant};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct State {
+    path: PathBuf,
+    previous: HashMap<PathBuf, Record>,
+    writer: Mutex<Writer>,
+}
+
+struct Writer {
+    file: BufWriter<File>,
+    last_flush: Instant,
+}
+
+struct Record {
+    ino: u64,
+    size: u64,
+    ctime: (i64, i64),
+    digest: [u8; 20],
+}
+
+impl State {
+    pub fn create(path: &Path, header: &[u8]) -> Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(header)?;
+        Ok(State::new(path, HashMap::new(), file))
+    }
+
+    pub fn resume(path: &Path, header: &[u8]) -> Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        // A header cut short by a crash is written again.
+        if header.starts_with(&contents) {
+            file.set_len(0)?;
+            file.write_all(header)?;
+            return Ok(State::new(path, HashMap::new(), file));
+        }
+        let Some(records) = contents.strip_prefix(header) else {
+            return Err("state was recorded for different directories or options".into());
+        };
+
+        let mut previous = HashMap::new();
+        let mut complete = header.len();
+        for record in records.split_inclusive(|&b| b == b'\0') {
+            let Some(record) = record.strip_suffix(b"\0") else {
+                break;
+            };
+            complete += record.len() + 1;
+            if let Some((path, record)) = parse(record) {
+                previous.insert(path, record);
+            }
+        }
+
+        // Drop any partially written record so that new records are appended
+        // after the last complete one.
+        file.set_len(complete as u64)?;
+
+        Ok(State::new(path, previous, file))
+    }
+
+    fn new(path: &Path, previous: HashMap<PathBuf, Record>, file: File) -> Self {
+        State {
+            path: path.to_owned(),
+            previous,
+            writer: Mutex::new(Writer {
+                file: BufWriter::new(file),
+                last_flush: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stat must include the ctime.
+    pub fn lookup(&self, path: &Path, stat: &Stat) -> Option<[u8; 20]> {
+        let record = self.previous.get(path)?;
+        if record.ino == stat.ino && record.size == stat.size && Some(record.ctime) == stat.ctime {
+            Some(record.digest)
+        } else {
+            None
+        }
+    }
+
+    /// The stat must include the ctime.
+    pub fn record(&self, path: &Path, stat: &Stat, digest: [u8; 20]) -> Result<()> {
+        let (ctime, ctime_nsec) = stat.ctime.unwrap();
+        let mut writer = self.writer.lock();
+        for byte in digest {
+            write!(writer.file, "{:02x}", byte)?;
+        }
+        write!(
+            writer.file,
+            " {} {} {}.{} ",
+            stat.ino, stat.size, ctime, ctime_nsec,
+        )?;
+        writer.file.write_all(path.as_os_str().as_bytes())?;
+        writer.file.write_all(b"\0")?;
+        if writer.last_flush.elapsed() >= FLUSH_INTERVAL {
+            writer.file.flush()?;
+            writer.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.writer.lock().file.flush()?;
+        Ok(())
+    }
+}
+
+/// The magic, then each option as `--name=value`, then the absolute path of
+/// each DIR, every one terminated by a NUL byte, and an empty record to end.
+pub fn header(options: &[(&str, String)], roots: &[PathBuf]) -> Vec<u8> {
+    let mut header = b"sha1dir-state\0".to_vec();
+    for (name, value) in options {
+        header.extend_from_slice(format!("--{}={}\0", name, value).as_bytes());
+    }
+    for root in roots {
+        header.extend_from_slice(root.as_os_str().as_bytes());
+        header.push(b'\0');
+    }
+    header.push(b'\0');
+    header
+}
+
+fn parse(record: &[u8]) -> Option<(PathBuf, Record)> {
+    let mut fields = record.splitn(5, |&b| b == b' ');
+    let digest = parse_hex(fields.next()?)?;
+    let ino = str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+    let size = str::from_utf8(fields.next()?).ok()?.parse().ok()?;
+    let (sec, nsec) = str::from_utf8(fields.next()?).ok()?.split_once('.')?;
+    let ctime = (sec.parse().ok()?, nsec.parse().ok()?);
+    let path = PathBuf::from(OsStr::from_bytes(fields.next()?));
+    let record = Record {
+        ino,
+        size,
+        ctime,
+        digest,
+    };
+    Some((path, record))
+}
+
+#[test]
+fn test_parse() {
+    let record = b"da39a3ee5e6b4b0d3255bfef95601890afd80709 42 7 1700000000.5 /tmp/a b";
+    let (path, record) = parse(record).unwrap();
+    assert_eq!(path, Path::new("/tmp/a b"));
+    assert_eq!(record.ino, 42);
+    assert_eq!(record.size, 7);
+    assert_eq!(record.ctime, (1_700_000_000, 5));
+    assert_eq!(record.digest[..2], [0xda, 0x39]);
+
+    assert!(parse(b"da39a3ee5e6b4b0d3255bfef95601890afd80709 42 7 1700000000 /a").is_none());
+    assert!(parse(b"da39 42 7 1700000000.5 /a").is_none());
+}
+
+#[test]
+fn test_resume() {
+    let path = std::env::temp_dir().join(format!("sha1dir-test-state-{}", std::process::id()));
+    let expected = header(&[("xattrs", "false".to_owned())], &[PathBuf::from("/dir")]);
+    let file = Path::new("/dir/file");
+    let stat = Stat {
+        mode: 0o100_644,
+        size: 7,
+        ino: 42,
+        dev: 1,
+        ctime: Some((1_700_000_000, 5)),
+        flags: None,
+    };
+    let digest = [1; 20];
+
+    let state = State::create(&path, &expected).unwrap();
+    state.record(file, &stat, digest).unwrap();
+    state.flush().unwrap();
+    drop(state);
+
+    let state = State::resume(&path, &expected).unwrap();
+    assert_eq!(state.lookup(file, &stat), Some(digest));
+    assert_eq!(state.lookup(Path::new("/dir/other"), &stat), None);
+    for changed in [
+        Stat { ino: 43, ..stat },
+        Stat { size: 8, ..stat },
+        Stat {
+            ctime: Some((1_700_000_000, 6)),
+            ..stat
+        },
+    ] {
+        assert_eq!(state.lookup(file, &changed), None);
+    }
+    drop(state);
+
+    let other = header(&[("xattrs", "true".to_owned())], &[PathBuf::from("/dir")]);
+    assert!(State::resume(&path, &other).is_err());
+    let other = header(&[("xattrs", "false".to_owned())], &[PathBuf::from("/")]);
+    assert!(State::resume(&path, &other).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,107 @@
+//! Detection of the kind of storage that a directory is on, to size the
+//! hashing threads for a device when no `-j` is given.
+//!
+//! Network filesystems are recognized by their statfs magic number, and
+//! rotational disks by the `queue/rotational` flag that Linux exposes for
+//! block devices in sysfs. Anything else, such as tmpfs or a filesystem
+//! spanning several devices, keeps the general default.
+
+use std::cmp;
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Storage {
+    #[cfg(target_os = "linux")]
+    Rotational,
+    #[cfg(target_os = "linux")]
+    SolidState,
+    #[cfg(target_os = "linux")]
+    Network,
+    Unknown,
+}
+
+impl Storage {
+    pub fn default_jobs(self) -> usize {
+        match self {
+            // Concurrent reads make a spinning disk seek back and forth.
+            #[cfg(target_os = "linux")]
+            Storage::Rotational => 2,
+            // Hashing is the bottleneck, not the device.
+            #[cfg(target_os = "linux")]
+            Storage::SolidState => num_cpus::get(),
+            // Throughput is bounded by latency rather than bandwidth.
+            #[cfg(target_os = "linux")]
+            Storage::Network => 32,
+            Storage::Unknown => cmp::min(num_cpus::get(), 8),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn detect(path: &Path, dev: u64) -> Storage {
+    if is_network_filesystem(path) {
+        Storage::Network
+    } else {
+        match rotational(dev) {
+            Some(true) => Storage::Rotational,
+            Some(false) => Storage::SolidState,
+            None => Storage::Unknown,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn detect(_path: &Path, _dev: u64) -> Storage {
+    Storage::Unknown
+}
+
+#[cfg(target_os = "linux")]
+fn is_network_filesystem(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    const NFS: u32 = 0x6969;
+    const SMB: u32 = 0x517b;
+    const CIFS: u32 = 0xff53_4d42;
+    const SMB2: u32 = 0xfe53_4d42;
+    const CEPH: u32 = 0x00c3_6400;
+    const AFS: u32 = 0x5346_414f;
+    const LUSTRE: u32 = 0x0bd0_0bd0;
+    const GPFS: u32 = 0x4750_4653;
+    const V9FS: u32 = 0x0102_1997;
+
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path.as_ptr(), buf.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    // The type of f_type differs between architectures, but the magic
+    // numbers are all 32 bits.
+    #[allow(clippy::cast_sign_loss)]
+    let magic = unsafe { buf.assume_init() }.f_type as u32;
+    matches!(
+        magic,
+        NFS | SMB | CIFS | SMB2 | CEPH | AFS | LUSTRE | GPFS | V9FS,
+    )
+}
+
+// The flag is on the whole disk, which is the parent of a partition in sysfs.
+#[cfg(target_os = "linux")]
+fn rotational(dev: u64) -> Option<bool> {
+    use std::fs;
+
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    let block = format!("/sys/dev/block/{}:{}", major, minor);
+    let flag = fs::read(format!("{}/queue/rotational", block))
+        .or_else(|_| fs::read(format!("{}/../queue/rotational", block)))
+        .ok()?;
+    match flag.as_slice() {
+        b"0\n" => Some(false),
+        b"1\n" => Some(true),
+        _ => None,
+    }
+}
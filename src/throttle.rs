@@ -0,0 +1,42 @@
+//! Limit on the aggregate rate at which file content is read, shared by all
+//! worker threads.
+//!
+//! Each worker reserves the next slot in a single schedule before reading a
+//! chunk, and sleeps until its slot comes up. Time spent idle is not banked,
+//! so there is no burst above the rate after a stretch of small files.
+
+use parking_lot::Mutex;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Granularity at which reads are scheduled.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+pub struct Throttle {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn acquire(&self, bytes: usize) {
+        let duration = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        let now = Instant::now();
+        let start = {
+            let mut next = self.next.lock();
+            let start = cmp::max(*next, now);
+            *next = start + duration;
+            start
+        };
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+}
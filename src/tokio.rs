@@ -0,0 +1,30 @@
+use crate::{Checksum, Checksummer, Errors};
+use std::panic;
+use std::path::Path;
+use tokio::task;
+
+impl Checksummer {
+    /// Hashes the directory at `path` like [`checksum`][Self::checksum],
+    /// without blocking the async runtime. Available with the `tokio`
+    /// feature.
+    ///
+    /// The walk runs on one of tokio's blocking threads, from which entries
+    /// are hashed in parallel on the `Checksummer`'s thread pool, or else the
+    /// global rayon pool. However many of these are awaited at once, no more
+    /// entries are hashed at a time than that pool has threads. Dropping the
+    /// future does not stop the walk; for that, see
+    /// [`Options::cancel`][crate::Options::cancel].
+    ///
+    /// # Errors
+    ///
+    /// Fails with every entry that could not be read, or whose type is not
+    /// supported, once the rest of the tree is hashed.
+    pub async fn checksum_async<P: AsRef<Path>>(&self, path: P) -> Result<Checksum, Errors> {
+        let checksummer = self.clone();
+        let path = path.as_ref().to_owned();
+        match task::spawn_blocking(move || checksummer.checksum(path)).await {
+            Ok(result) => result,
+            Err(error) => panic::resume_unwind(error.into_panic()),
+        }
+    }
+}
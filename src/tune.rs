@@ -0,0 +1,86 @@
+//! Adjustment of the number of files hashed at once on a device for `-j
+//! auto`, by hill climbing on the observed throughput.
+//!
+//! The limit starts low and is scaled by half again each interval, for as long
+//! as throughput keeps improving. When an interval comes out slower than the
+//! one before, the direction reverses, so the limit settles into oscillating
+//! around whatever concurrency the storage handles best. Each entry counts
+//! for some work in addition to its size, so that trees of tiny files, whose
+//! cost is in metadata, are measured sensibly too.
+
+use parking_lot::{Condvar, Mutex};
+use std::cmp;
+use std::time::{Duration, Instant};
+
+pub const MAX_JOBS: usize = 64;
+const INITIAL_JOBS: usize = 4;
+const INTERVAL: Duration = Duration::from_millis(500);
+const ENTRY_COST: u64 = 4096;
+
+pub struct Tuner {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+struct State {
+    limit: usize,
+    active: usize,
+    work: u64,
+    since: Instant,
+    previous_rate: f64,
+    growing: bool,
+}
+
+impl Tuner {
+    pub fn new() -> Self {
+        Tuner {
+            state: Mutex::new(State {
+                limit: INITIAL_JOBS,
+                active: 0,
+                work: 0,
+                since: Instant::now(),
+                previous_rate: 0.0,
+                growing: true,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Waits until the current limit allows one more file to be hashed.
+    pub fn acquire(&self) {
+        let mut state = self.state.lock();
+        while state.active >= state.limit {
+            self.available.wait(&mut state);
+        }
+        state.active += 1;
+    }
+
+    pub fn release(&self, bytes: u64, entries: usize) {
+        let mut state = self.state.lock();
+        state.active -= 1;
+        state.work += bytes + entries as u64 * ENTRY_COST;
+        let elapsed = state.since.elapsed();
+        if elapsed >= INTERVAL {
+            state.adjust(elapsed);
+        }
+        drop(state);
+        self.available.notify_all();
+    }
+}
+
+impl State {
+    fn adjust(&mut self, elapsed: Duration) {
+        let rate = self.work as f64 / elapsed.as_secs_f64();
+        if rate < self.previous_rate {
+            self.growing = !self.growing;
+        }
+        self.limit = if self.growing {
+            cmp::min(cmp::max(self.limit * 3 / 2, self.limit + 1), MAX_JOBS)
+        } else {
+            cmp::max(self.limit * 2 / 3, 1)
+        };
+        self.previous_rate = rate;
+        self.work = 0;
+        self.since = Instant::now();
+    }
+}
@@ -0,0 +1,608 @@
+//! Traversal of directories as a pipeline.
+//!
+//! Directories are listed on a thread pool of their own, and the entries
+//! found in them are handed in batches through a bounded queue to the hashing
+//! threads. Listing stalls while the queue is full, so memory stays flat
+//! however many entries a directory has. Likewise subdirectories beyond a
+//! limit are listed by the thread that found them, rather than each held as a
+//! pending task.
+//!
+//! Every device that the trees span gets a queue and hashing threads of its
+//! own, started when the first of its entries is found, so that a slow device
+//! does not hold up the others. Several trees are walked at once, sharing
+//! the threads of any device they have in common.
+//!
+//! Entries keep the directory that they are in open until they are hashed.
+//! To stay within the limit on open descriptors, past half of it directories
+//! are instead closed as soon as they are listed, and what is in them is
+//! opened by path from the root.
+//!
+//! With `--traversal bfs-bounded`, subdirectories are not spawned as tasks
+//! but put in a frontier that is listed one level at a time, each held as
+//! just its path until then. Past a limit on its size, subdirectories are
+//! listed right away by the thread that found them instead.
+//!
+//! The path of each entry is built in a buffer reused across a listing and
+//! copied into one buffer shared by its whole batch, rather than allocated by
+//! itself.
+
+use crate::dirfd::{self, At, Dir};
+use crate::order;
+use crate::rlimit;
+use crate::stat::{self, Stat};
+use crate::storage;
+use crate::tune::{self, Tuner};
+use crate::{advice, begin_entry, die, file, socket, symlink, Context, Jobs, Result};
+use clap::ValueEnum;
+use parking_lot::Mutex;
+use rayon::{Scope, ThreadPool, ThreadPoolBuilder};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::mem;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+
+// Small entries are hashed in batches, as handing over each one costs more
+// than hashing it in trees of many tiny files.
+const BATCH_LEN: usize = 64;
+const BATCH_BYTES: u64 = 4 << 20;
+
+const QUEUED_BATCHES_PER_THREAD: usize = 4;
+const MAX_PENDING_DIRS: usize = 1024;
+const MAX_FRONTIER: usize = 1 << 16;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Debug)]
+struct Config {
+    pool: ThreadPool,
+    jobs: Option<Jobs>,
+    traversal: Traversal,
+    max_open_dirs: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum Traversal {
+    /// Each subdirectory as a task of its own as soon as it is found,
+    /// depth first past a limit on the number of pending tasks
+    Dfs,
+    /// One level at a time, with a bounded number of directories found but
+    /// not yet listed, each held as just its path
+    BfsBounded,
+}
+
+struct Entry<'a> {
+    cx: &'a Context<'a>,
+    // Within the paths of the batch that the entry is in.
+    path: Range<usize>,
+    // The directory that contains the entry, or the root if the entry is
+    // opened by its whole path.
+    dir: Arc<Dir>,
+    by_path: bool,
+    stat: Stat,
+}
+
+// Entries of one device with their sort key, and a directory on the device.
+struct Sorted<'a> {
+    dir: PathBuf,
+    paths: Paths,
+    entries: Vec<(u64, Entry<'a>)>,
+}
+
+struct Batch<'a> {
+    paths: Paths,
+    entries: Vec<Entry<'a>>,
+}
+
+// Paths one after another in a single buffer.
+#[derive(Default)]
+struct Paths {
+    bytes: Vec<u8>,
+}
+
+impl Paths {
+    fn push(&mut self, path: &Path) -> Range<usize> {
+        let start = self.bytes.len();
+        self.bytes.extend_from_slice(path.as_os_str().as_bytes());
+        start..self.bytes.len()
+    }
+
+    fn get(&self, range: Range<usize>) -> &Path {
+        Path::new(OsStr::from_bytes(&self.bytes[range]))
+    }
+}
+
+// A directory to list with the next level. It is opened by its path from the
+// root, unless the path is too long, in which case its parent is kept open.
+struct Pending<'a> {
+    cx: &'a Context<'a>,
+    path: PathBuf,
+    parent: Arc<Dir>,
+    by_path: bool,
+    root: Arc<Dir>,
+    metadata: Option<Stat>,
+}
+
+struct Walker<'a, 's, 'env> {
+    jobs: Option<Jobs>,
+    thread_scope: &'s thread::Scope<'s, 'env>,
+    devices: Mutex<HashMap<u64, SyncSender<Batch<'a>>>>,
+    sorted: Mutex<HashMap<u64, Sorted<'a>>>,
+    pending_dirs: AtomicUsize,
+    // Only with --traversal bfs-bounded.
+    frontier: Option<Mutex<Vec<Pending<'a>>>>,
+    max_open_dirs: usize,
+}
+
+/// Jobs are the number of hashing threads for each device, by default
+/// according to the kind of storage.
+pub fn configure(walk_threads: usize, jobs: Option<Jobs>, traversal: Traversal) {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(walk_threads)
+        .build()
+        .unwrap();
+
+    let max_open_dirs = rlimit::nofile().map_or(usize::MAX, |nofile| {
+        usize::try_from(nofile / 2).unwrap_or(usize::MAX)
+    });
+
+    // This is the only time the walk pool is initialized.
+    let config = Config {
+        pool,
+        jobs,
+        traversal,
+        max_open_dirs,
+    };
+    CONFIG.set(config).unwrap();
+}
+
+/// Hashes the directory at the root of each context into its checksum. The
+/// roots must be absolute.
+pub fn checksum_dirs(cxs: &[Context]) {
+    let config = CONFIG.get().unwrap();
+    walk(config, cxs, config.jobs);
+}
+
+/// Like `checksum_dirs` but with this many hashing threads for each device,
+/// whatever was configured.
+pub fn checksum_dirs_with_jobs(cxs: &[Context], jobs: Jobs) {
+    let config = CONFIG.get().unwrap();
+    walk(config, cxs, Some(jobs));
+}
+
+fn walk(config: &Config, cxs: &[Context], jobs: Option<Jobs>) {
+    // Hashing threads are not part of the global pool, which is left free
+    // for the chunks of files hashed by --chunked.
+    thread::scope(|thread_scope| {
+        let walker = Walker {
+            jobs,
+            thread_scope,
+            devices: Mutex::new(HashMap::new()),
+            sorted: Mutex::new(HashMap::new()),
+            pending_dirs: AtomicUsize::new(0),
+            frontier: match config.traversal {
+                Traversal::Dfs => None,
+                Traversal::BfsBounded => Some(Mutex::new(Vec::new())),
+            },
+            max_open_dirs: config.max_open_dirs,
+        };
+        config.pool.scope(|scope| {
+            for cx in cxs {
+                let walker = &walker;
+                scope.spawn(move |scope| {
+                    let root = match Dir::Cwd.open_dir(cx.root) {
+                        Ok(root) => Arc::new(root),
+                        Err(error) => die(cx.label, error),
+                    };
+                    if let Err(error) = walker.children(scope, cx, Path::new(""), &root, &root) {
+                        die(cx.label, error);
+                    }
+                });
+            }
+        });
+        if let Some(frontier) = &walker.frontier {
+            loop {
+                let level = mem::take(&mut *frontier.lock());
+                if level.is_empty() {
+                    break;
+                }
+                config.pool.scope(|scope| {
+                    for pending in level {
+                        let walker = &walker;
+                        scope.spawn(move |scope| {
+                            let at = At {
+                                dir: &pending.parent,
+                                name: open_name(&pending.path, pending.by_path),
+                            };
+                            let (cx, path, metadata) =
+                                (pending.cx, &pending.path, pending.metadata);
+                            walker.dir(scope, cx, path, at, &pending.root, metadata);
+                        });
+                    }
+                });
+            }
+        }
+        walker.send_sorted();
+        // Closes the queues, so the hashing threads finish once they are
+        // empty.
+        drop(walker);
+    });
+}
+
+impl<'a: 's, 's> Walker<'a, 's, '_> {
+    fn dir<'scope>(
+        &'scope self,
+        scope: &Scope<'scope>,
+        cx: &'a Context<'a>,
+        path: &Path,
+        at: At,
+        root: &Arc<Dir>,
+        metadata: Option<Stat>,
+    ) {
+        let result = at.dir.open_dir(at.name).and_then(|dir| {
+            // Not yet stat'ed if the listing said that it is a directory.
+            let metadata = match metadata {
+                Some(metadata) => metadata,
+                None => dir.stat(cx.stat_fields)?,
+            };
+            Ok((dir, metadata))
+        });
+        let (dir, metadata) = match result {
+            Ok(opened) => opened,
+            Err(error) => die(cx.label.join(path), error),
+        };
+
+        // Entries within a directory outside the shard may still be within it.
+        if cx.in_shard(path) {
+            if let Some(dry_run) = &cx.dry_run {
+                dry_run.add(path, &metadata);
+            } else {
+                match begin_entry(cx, path, at, &metadata, b'd') {
+                    Ok(sha) => cx.put_dir(path, sha),
+                    Err(error) => die(cx.label.join(path), error),
+                }
+            }
+        }
+
+        if let Err(error) = self.children(scope, cx, path, &Arc::new(dir), root) {
+            die(cx.label.join(path), error);
+        }
+    }
+
+    // The path of the root is empty, so that its children's paths have no
+    // leading `./`.
+    fn children<'scope>(
+        &'scope self,
+        scope: &Scope<'scope>,
+        cx: &'a Context<'a>,
+        path: &Path,
+        dir: &Arc<Dir>,
+        root: &Arc<Dir>,
+    ) -> Result<()> {
+        let absolute = cx.root.join(path);
+        let keep = dirfd::open_dirs() <= self.max_open_dirs;
+
+        let mut batcher = Batcher::new();
+        let mut found = Vec::new();
+        let mut found_paths = Paths::default();
+        let mut buf = path.as_os_str().as_bytes().to_vec();
+        let mut read_dir = dir.read_dir()?;
+        while let Some(child) = read_dir.next_entry() {
+            let child = child?;
+            buf.truncate(path.as_os_str().len());
+            if !buf.is_empty() {
+                buf.push(b'/');
+            }
+            buf.extend_from_slice(child.name.as_bytes());
+            let child_path = Path::new(OsStr::from_bytes(&buf));
+            let at = At {
+                dir,
+                name: Path::new(child.name),
+            };
+            let metadata = if child.is_dir() {
+                None
+            } else {
+                match stat::lstat(at, cx.stat_fields) {
+                    Ok(metadata) => Some(metadata),
+                    Err(error) => die(cx.label.join(child_path), error),
+                }
+            };
+
+            // A path too long to open by is opened from its directory
+            // regardless.
+            let short = child_path.as_os_str().len() < libc::PATH_MAX as usize;
+
+            let metadata = match metadata {
+                Some(metadata) if !metadata.is_dir() => metadata,
+                metadata => {
+                    let by_path = !keep && short;
+                    let parent = if by_path { root } else { dir };
+                    if let Some(frontier) = &self.frontier {
+                        let mut frontier = frontier.lock();
+                        if frontier.len() < MAX_FRONTIER {
+                            frontier.push(Pending {
+                                cx,
+                                path: child_path.to_owned(),
+                                parent: Arc::clone(if short { root } else { dir }),
+                                by_path: short,
+                                root: Arc::clone(root),
+                                metadata,
+                            });
+                            continue;
+                        }
+                    } else if self.pending_dirs.fetch_add(1, Ordering::Relaxed) < MAX_PENDING_DIRS {
+                        let child_path = child_path.to_owned();
+                        let parent = Arc::clone(parent);
+                        let root = Arc::clone(root);
+                        scope.spawn(move |scope| {
+                            let at = At {
+                                dir: &parent,
+                                name: open_name(&child_path, by_path),
+                            };
+                            self.dir(scope, cx, &child_path, at, &root, metadata);
+                            self.pending_dirs.fetch_sub(1, Ordering::Relaxed);
+                        });
+                        continue;
+                    } else {
+                        self.pending_dirs.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    // Listed by this thread before going on.
+                    let at = At {
+                        dir: parent,
+                        name: open_name(child_path, by_path),
+                    };
+                    self.dir(scope, cx, child_path, at, root, metadata);
+                    continue;
+                }
+            };
+
+            if !cx.in_shard(child_path) {
+                continue;
+            }
+            if let Some(order) = cx.order {
+                let key = order::key(order, at, &metadata);
+                // Holding every directory open until the whole tree is listed
+                // could take more descriptors than there are.
+                let by_path = short;
+                let entry = Entry {
+                    cx,
+                    path: found_paths.push(child_path),
+                    dir: Arc::clone(if by_path { root } else { dir }),
+                    by_path,
+                    stat: metadata,
+                };
+                found.push((key, entry));
+                continue;
+            }
+            if cx.readahead && metadata.is_file() {
+                advice::prefetch(at);
+            }
+            let by_path = !keep && short;
+            let entry = Entry {
+                cx,
+                path: 0..0,
+                dir: Arc::clone(if by_path { root } else { dir }),
+                by_path,
+                stat: metadata,
+            };
+            if let Some(batch) = batcher.push(child_path, entry) {
+                self.send(&absolute, batch);
+            }
+        }
+        if let Some(batch) = batcher.finish() {
+            self.send(&absolute, batch);
+        }
+        if let Some((_key, entry)) = found.first() {
+            let mut sorted = self.sorted.lock();
+            let sorted = sorted.entry(entry.stat.dev).or_insert_with(|| Sorted {
+                dir: absolute,
+                paths: Paths::default(),
+                entries: Vec::new(),
+            });
+            for (key, mut entry) in found {
+                entry.path = sorted.paths.push(found_paths.get(entry.path));
+                sorted.entries.push((key, entry));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Called once the whole tree is listed, with --order. Each device is fed
+    // from a thread of its own to keep them all busy.
+    fn send_sorted(&self) {
+        let sorted = mem::take(&mut *self.sorted.lock());
+        for (dev, sorted) in sorted {
+            let Sorted {
+                dir,
+                paths,
+                mut entries,
+            } = sorted;
+            let sender = self.sender(&dir, dev);
+            self.thread_scope.spawn(move || {
+                entries.sort_by_key(|(key, _entry)| *key);
+                let mut batcher = Batcher::new();
+                for (_key, entry) in entries {
+                    let path = paths.get(entry.path.clone());
+                    if let Some(batch) = batcher.push(path, entry) {
+                        let _ = sender.send(batch);
+                    }
+                }
+                if let Some(batch) = batcher.finish() {
+                    let _ = sender.send(batch);
+                }
+            });
+        }
+    }
+
+    fn send(&self, dir: &Path, batch: Batch<'a>) {
+        // Entries of the same directory are on the same device. Only a
+        // directory can be a mount point.
+        let dev = batch.entries[0].stat.dev;
+        // Only fails if every hashing thread is gone, which means the process
+        // is already exiting with an error.
+        let _ = self.sender(dir, dev).send(batch);
+    }
+
+    fn sender(&self, dir: &Path, dev: u64) -> SyncSender<Batch<'a>> {
+        self.devices
+            .lock()
+            .entry(dev)
+            .or_insert_with(|| self.start_device(dir, dev))
+            .clone()
+    }
+
+    fn start_device(&self, dir: &Path, dev: u64) -> SyncSender<Batch<'a>> {
+        let jobs = self.jobs.unwrap_or_else(|| {
+            let storage = storage::detect(dir, dev);
+            Jobs::Threads(storage.default_jobs())
+        });
+        let (threads, tuner) = match jobs {
+            Jobs::Threads(threads) => (threads, None),
+            Jobs::Auto => (tune::MAX_JOBS, Some(Arc::new(Tuner::new()))),
+        };
+        let (sender, receiver) = mpsc::sync_channel(threads * QUEUED_BATCHES_PER_THREAD);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            let tuner = tuner.clone();
+            self.thread_scope
+                .spawn(move || hash(&receiver, tuner.as_deref()));
+        }
+        sender
+    }
+}
+
+struct Batcher<'a> {
+    batch: Batch<'a>,
+    bytes: u64,
+}
+
+impl<'a> Batcher<'a> {
+    fn new() -> Self {
+        Batcher {
+            batch: Batch {
+                paths: Paths::default(),
+                entries: Vec::new(),
+            },
+            bytes: 0,
+        }
+    }
+
+    // Returns a batch once there is one to hash. Large files are hashed by
+    // themselves.
+    fn push(&mut self, path: &Path, mut entry: Entry<'a>) -> Option<Batch<'a>> {
+        let size = entry.stat.size;
+        if size >= BATCH_BYTES {
+            let mut paths = Paths::default();
+            entry.path = paths.push(path);
+            let entries = vec![entry];
+            return Some(Batch { paths, entries });
+        }
+        self.bytes += size;
+        entry.path = self.batch.paths.push(path);
+        self.batch.entries.push(entry);
+        if self.batch.entries.len() == BATCH_LEN || self.bytes >= BATCH_BYTES {
+            self.bytes = 0;
+            // The next batch's paths likely take as much room.
+            let paths = Paths {
+                bytes: Vec::with_capacity(self.batch.paths.bytes.len()),
+            };
+            let next = Batch {
+                paths,
+                entries: Vec::with_capacity(BATCH_LEN),
+            };
+            Some(mem::replace(&mut self.batch, next))
+        } else {
+            None
+        }
+    }
+
+    fn finish(self) -> Option<Batch<'a>> {
+        if self.batch.entries.is_empty() {
+            None
+        } else {
+            Some(self.batch)
+        }
+    }
+}
+
+fn hash(receiver: &Mutex<Receiver<Batch>>, tuner: Option<&Tuner>) {
+    loop {
+        if let Some(tuner) = tuner {
+            tuner.acquire();
+        }
+        let Ok(batch) = receiver.lock().recv() else {
+            if let Some(tuner) = tuner {
+                tuner.release(0, 0);
+            }
+            return;
+        };
+        let bytes = batch.entries.iter().map(|entry| entry.stat.size).sum();
+        let entries = batch.entries.len();
+        for entry in &batch.entries {
+            hash_entry(entry, batch.paths.get(entry.path.clone()));
+        }
+        if let Some(tuner) = tuner {
+            tuner.release(bytes, entries);
+        }
+    }
+}
+
+// The name to open an entry by, relative to the root or to its directory.
+fn open_name(path: &Path, by_path: bool) -> &Path {
+    if by_path {
+        path
+    } else {
+        Path::new(path.file_name().unwrap())
+    }
+}
+
+fn hash_entry(entry: &Entry, path: &Path) {
+    let cx = entry.cx;
+    let metadata = entry.stat;
+    if let Some(dry_run) = &cx.dry_run {
+        let unknown = !metadata.is_file()
+            && !metadata.is_symlink()
+            && !metadata.is_socket()
+            && !metadata.is_door_or_port();
+        if unknown && !cx.ignore_unknown_filetypes {
+            die(cx.label.join(path), "Unsupported file type");
+        }
+        let skipped = unknown || metadata.is_door_or_port() && cx.skip_doors;
+        if !skipped {
+            dry_run.add(path, &metadata);
+        }
+        return;
+    }
+    let at = At {
+        dir: &entry.dir,
+        name: open_name(path, entry.by_path),
+    };
+    let result = if metadata.is_file() {
+        file(cx, path, at, metadata)
+    } else if metadata.is_symlink() {
+        symlink(cx, path, at, metadata)
+    } else if metadata.is_socket() {
+        socket(cx, path, at, metadata)
+    } else if metadata.is_door_or_port() {
+        if cx.skip_doors {
+            Ok(())
+        } else {
+            socket(cx, path, at, metadata)
+        }
+    } else if cx.ignore_unknown_filetypes {
+        Ok(())
+    } else {
+        die(cx.label.join(path), "Unsupported file type");
+    };
+
+    if let Err(error) = result {
+        die(cx.label.join(path), error);
+    }
+}
@@ -0,0 +1,294 @@
+//! Extended attributes of each entry, for --xattrs.
+//!
+//! Attributes are read without following symlinks, through a path that
+//! names the entry relative to the descriptor of its directory: on Linux by
+//! way of /proc/self/fd, and on macOS and FreeBSD from the directory's own
+//! path as given by `F_GETPATH` and `F_KINFO` respectively. Every attribute
+//! the system lists is included, whatever its namespace, which on macOS
+//! covers the resource fork and Finder info as `com.apple.ResourceFork` and
+//! `com.apple.FinderInfo`. On a filesystem that does not support extended
+//! attributes, every entry is taken to have none.
+//!
+//! FreeBSD keeps the names of each namespace apart rather than prefixing
+//! them, so they are given the prefix `user.` or `system.` here, as on Linux.
+//! The system namespace is readable only by root, and is left out for other
+//! users.
+
+use crate::dirfd::At;
+use std::io;
+
+pub const SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+));
+
+/// Each name and value, sorted by name.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+))]
+pub fn read(at: At) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let path = path(at)?;
+    let names = match sys::list(&path) {
+        Ok(names) => names,
+        Err(error) if error.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let mut attrs = Vec::new();
+    for name in names {
+        match sized(|buf, size| sys::get(&path, &name, buf, size)) {
+            Ok(value) => attrs.push((sys::full_name(name), value)),
+            // Removed since it was listed.
+            Err(error) if error.raw_os_error() == Some(sys::ENOATTR) => {}
+            Err(error) => return Err(error),
+        }
+    }
+    attrs.sort();
+    Ok(attrs)
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+)))]
+pub fn read(_at: At) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    unreachable!()
+}
+
+// Calls `f` first to learn the size, then again to fill a buffer that size,
+// over again if it grew in between. The buffer has a byte to spare so that
+// growth is noticed even where the result is cut short to fit rather than
+// failing with ERANGE, as on FreeBSD.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+))]
+fn sized(mut f: impl FnMut(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let Ok(size) = usize::try_from(f(std::ptr::null_mut(), 0)) else {
+            return Err(io::Error::last_os_error());
+        };
+        // A size of zero would only ask for the size again.
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let mut buf = Vec::<u8>::with_capacity(size + 1);
+        if let Ok(len) = usize::try_from(f(buf.as_mut_ptr().cast(), size + 1)) {
+            if len <= size {
+                unsafe { buf.set_len(len) };
+                return Ok(buf);
+            }
+            continue;
+        }
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+// Names as listed by Linux and macOS, each terminated by a NUL.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+))]
+fn split_names(names: &[u8]) -> io::Result<Vec<std::ffi::CString>> {
+    use std::ffi::CString;
+
+    let names = names
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty());
+    Ok(names.map(CString::new).collect::<Result<_, _>>()?)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn path(at: At) -> io::Result<std::ffi::CString> {
+    use crate::dirfd::Dir;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut path = match at.dir {
+        Dir::Cwd => Vec::new(),
+        Dir::Fd(_) => format!("/proc/self/fd/{}/", at.dir.raw_fd()).into_bytes(),
+    };
+    path.extend_from_slice(at.name.as_os_str().as_bytes());
+    Ok(CString::new(path)?)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn path(at: At) -> io::Result<std::ffi::CString> {
+    use crate::dirfd::Dir;
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut path = match at.dir {
+        Dir::Cwd => Vec::new(),
+        Dir::Fd(_) => {
+            let mut buf = vec![0u8; libc::PATH_MAX as usize];
+            if unsafe { libc::fcntl(at.dir.raw_fd(), libc::F_GETPATH, buf.as_mut_ptr()) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let len = CStr::from_bytes_until_nul(&buf).map_or(0, |dir| dir.to_bytes().len());
+            buf.truncate(len);
+            buf.push(b'/');
+            buf
+        }
+    };
+    path.extend_from_slice(at.name.as_os_str().as_bytes());
+    Ok(CString::new(path)?)
+}
+
+// F_KINFO is in FreeBSD 13.1 and later.
+#[cfg(target_os = "freebsd")]
+fn path(at: At) -> io::Result<std::ffi::CString> {
+    use crate::dirfd::Dir;
+    use std::ffi::{CStr, CString};
+    use std::mem::{self, MaybeUninit};
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut path = match at.dir {
+        Dir::Cwd => Vec::new(),
+        Dir::Fd(_) => {
+            let mut info = MaybeUninit::<libc::kinfo_file>::zeroed();
+            let info = unsafe {
+                let structsize = mem::size_of::<libc::kinfo_file>() as libc::c_int;
+                (*info.as_mut_ptr()).kf_structsize = structsize;
+                if libc::fcntl(at.dir.raw_fd(), libc::F_KINFO, info.as_mut_ptr()) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                info.assume_init()
+            };
+            let dir = unsafe { CStr::from_ptr(info.kf_path.as_ptr()) };
+            let mut buf = dir.to_bytes().to_vec();
+            buf.push(b'/');
+            buf
+        }
+    };
+    path.extend_from_slice(at.name.as_os_str().as_bytes());
+    Ok(CString::new(path)?)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::io;
+
+    pub const ENOATTR: libc::c_int = libc::ENODATA;
+
+    pub type Name = CString;
+
+    pub fn list(path: &CStr) -> io::Result<Vec<Name>> {
+        let names =
+            super::sized(|buf, size| unsafe { libc::llistxattr(path.as_ptr(), buf.cast(), size) })?;
+        super::split_names(&names)
+    }
+
+    pub fn get(path: &CStr, name: &Name, buf: *mut libc::c_void, size: usize) -> isize {
+        unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, size) }
+    }
+
+    pub fn full_name(name: Name) -> Vec<u8> {
+        name.into_bytes()
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::io;
+
+    pub const ENOATTR: libc::c_int = libc::ENOATTR;
+
+    pub type Name = CString;
+
+    pub fn list(path: &CStr) -> io::Result<Vec<Name>> {
+        let names = super::sized(|buf, size| unsafe {
+            libc::listxattr(path.as_ptr(), buf.cast(), size, libc::XATTR_NOFOLLOW)
+        })?;
+        super::split_names(&names)
+    }
+
+    pub fn get(path: &CStr, name: &Name, buf: *mut libc::c_void, size: usize) -> isize {
+        let flags = libc::XATTR_NOFOLLOW;
+        unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size, 0, flags) }
+    }
+
+    pub fn full_name(name: Name) -> Vec<u8> {
+        name.into_bytes()
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+mod sys {
+    use std::ffi::{CStr, CString};
+    use std::io;
+
+    pub const ENOATTR: libc::c_int = libc::ENOATTR;
+
+    const NAMESPACES: [(libc::c_int, &str); 2] = [
+        (libc::EXTATTR_NAMESPACE_USER, "user."),
+        (libc::EXTATTR_NAMESPACE_SYSTEM, "system."),
+    ];
+
+    pub struct Name {
+        namespace: libc::c_int,
+        prefix: &'static str,
+        name: CString,
+    }
+
+    pub fn list(path: &CStr) -> io::Result<Vec<Name>> {
+        let mut list = Vec::new();
+        for (namespace, prefix) in NAMESPACES {
+            let names = match super::sized(|buf, size| unsafe {
+                libc::extattr_list_link(path.as_ptr(), namespace, buf, size)
+            }) {
+                Ok(names) => names,
+                Err(error)
+                    if namespace == libc::EXTATTR_NAMESPACE_SYSTEM
+                        && error.raw_os_error() == Some(libc::EPERM) =>
+                {
+                    continue;
+                }
+                Err(error) => return Err(error),
+            };
+            // Each is preceded by its length in one byte, with no NUL.
+            let mut rest = names.as_slice();
+            while let Some((&len, after)) = rest.split_first() {
+                let (name, after) = after.split_at(usize::from(len).min(after.len()));
+                list.push(Name {
+                    namespace,
+                    prefix,
+                    name: CString::new(name)?,
+                });
+                rest = after;
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn get(path: &CStr, name: &Name, buf: *mut libc::c_void, size: usize) -> isize {
+        let attr = name.name.as_ptr();
+        unsafe { libc::extattr_get_link(path.as_ptr(), name.namespace, attr, buf, size) }
+    }
+
+    pub fn full_name(name: Name) -> Vec<u8> {
+        let mut full = name.prefix.as_bytes().to_vec();
+        full.extend_from_slice(name.name.as_bytes());
+        full
+    }
+}
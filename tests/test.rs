@@ -0,0 +1,315 @@
+#![allow(clippy::uninlined_format_args)]
+
+use sha1dir::{Checksum, Checksummer, Decision, EncodeMetadata, EntryKind, Error, Options};
+use sha1dir::{UnknownAction, FORMAT_V1, FORMAT_VERSION};
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::io::{self, Write};
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+struct Fixture {
+    dir: PathBuf,
+}
+
+impl Fixture {
+    // A file larger than the library's read buffer, an empty file, nested
+    // and empty directories, a symlink and a socket.
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("sha1dir-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        fs::create_dir(dir.join("empty")).unwrap();
+        fs::write(dir.join("file"), b"content\n").unwrap();
+        fs::write(dir.join("zero"), b"").unwrap();
+        let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("sub/large"), large).unwrap();
+        fs::write(dir.join("sub/deeper/name with spaces"), b"x").unwrap();
+        symlink("../file", dir.join("sub/link")).unwrap();
+        drop(UnixListener::bind(dir.join("socket")).unwrap());
+        Fixture { dir }
+    }
+
+    fn add_fifo(&self) {
+        let status = Command::new("mkfifo")
+            .arg(self.dir.join("fifo"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn checksum(options: &Options, dir: &Path) -> Checksum {
+    options.build().unwrap().checksum(dir).unwrap()
+}
+
+fn visited(options: &mut Options, dir: &Path) -> BTreeMap<PathBuf, Checksum> {
+    let digests = Arc::new(Mutex::new(BTreeMap::new()));
+    let sink = Arc::clone(&digests);
+    options.visit(move |path, digest| {
+        sink.lock().unwrap().insert(path.to_owned(), digest);
+    });
+    checksum(options, dir);
+    let digests = digests.lock().unwrap().clone();
+    digests
+}
+
+fn xor<'a>(digests: impl IntoIterator<Item = &'a Checksum>) -> Checksum {
+    let mut bytes = [0; 20];
+    for digest in digests {
+        for (lhs, rhs) in bytes.iter_mut().zip(digest.as_bytes()) {
+            *lhs ^= rhs;
+        }
+    }
+    Checksum::from(bytes)
+}
+
+#[cfg(feature = "cli")]
+fn cli(args: &[&str], dir: &Path) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_sha1dir"))
+        .args(args)
+        .arg(dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn test_matches_cli() {
+    let fixture = Fixture::new("cli");
+    let dir = &fixture.dir;
+
+    let expected = cli(&[], dir);
+    let from_lib = Checksummer::new().checksum(dir).unwrap();
+    assert_eq!(expected, format!("{}  {}\n", from_lib, dir.display()));
+
+    // Every entry's digest, not only their XOR.
+    let manifest = cli(&["--manifest"], dir);
+    let mut lines = manifest.lines().filter(|line| !line.is_empty());
+    assert_eq!(lines.next().unwrap(), expected.trim_end());
+    let from_cli: BTreeMap<PathBuf, Checksum> = lines
+        .map(|line| {
+            let (digest, path) = line.split_once("  ").unwrap();
+            let path = Path::new(path).strip_prefix(dir).unwrap();
+            (path.to_owned(), digest.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(visited(&mut Options::new(), dir), from_cli);
+
+    let pinned = cli(&["--format-version", &FORMAT_V1.to_string()], dir);
+    assert_eq!(pinned, expected);
+
+    fixture.add_fifo();
+    let ignored = cli(&["--ignore-unknown-filetypes"], dir);
+    let from_lib = checksum(Options::new().ignore_unknown_filetypes(true), dir);
+    assert_eq!(ignored, format!("{}  {}\n", from_lib, dir.display()));
+    assert_eq!(ignored, expected);
+
+    // A dry run refuses the same entries as hashing does.
+    for dry_run in ["--list", "--estimate"] {
+        let status = Command::new(env!("CARGO_BIN_EXE_sha1dir"))
+            .arg(dry_run)
+            .arg(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert_eq!(status.code(), Some(1));
+    }
+}
+
+#[test]
+fn test_visit() {
+    let fixture = Fixture::new("visit");
+    let dir = &fixture.dir;
+
+    let digests = visited(&mut Options::new(), dir);
+    assert_eq!(xor(digests.values()), checksum(&Options::new(), dir));
+    let paths: Vec<&Path> = digests.keys().map(PathBuf::as_path).collect();
+    assert_eq!(
+        paths,
+        [
+            "empty",
+            "file",
+            "socket",
+            "sub",
+            "sub/deeper",
+            "sub/deeper/name with spaces",
+            "sub/large",
+            "sub/link",
+            "zero",
+        ]
+        .map(Path::new),
+    );
+}
+
+#[test]
+fn test_filter() {
+    let fixture = Fixture::new("filter");
+    let dir = &fixture.dir;
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let record = Arc::clone(&seen);
+    let mut options = Options::new();
+    options.filter(move |path, _metadata| {
+        record.lock().unwrap().push(path.to_owned());
+        if path == Path::new("sub") {
+            Decision::Exclude
+        } else {
+            Decision::Include
+        }
+    });
+
+    // As if the excluded directory did not exist, rather than empty.
+    let all = visited(&mut Options::new(), dir);
+    let kept = all.iter().filter(|(path, _)| !path.starts_with("sub"));
+    let expected = xor(kept.map(|(_, digest)| digest));
+    assert_eq!(checksum(&options, dir), expected);
+
+    // Nothing under an excluded directory is offered to the filter.
+    let seen = seen.lock().unwrap();
+    assert!(seen.iter().any(|path| path == Path::new("sub")));
+    assert!(!seen
+        .iter()
+        .any(|path| path.starts_with("sub") && path != Path::new("sub")));
+}
+
+#[test]
+fn test_cancel() {
+    let fixture = Fixture::new("cancel");
+    let cancel = Arc::new(AtomicBool::new(true));
+    let checksummer = Options::new().cancel(cancel).build().unwrap();
+    let errors = checksummer.checksum(&fixture.dir).unwrap_err();
+    assert!(errors
+        .iter()
+        .any(|error| matches!(error, Error::Cancelled { .. })));
+}
+
+#[test]
+fn test_unknown_filetypes() {
+    let fixture = Fixture::new("unknown");
+    let dir = &fixture.dir;
+    let without = checksum(&Options::new(), dir);
+    fixture.add_fifo();
+
+    let errors = Checksummer::new().checksum(dir).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    let error = errors.iter().next().unwrap();
+    assert!(matches!(error, Error::UnsupportedFileType { .. }));
+    assert_eq!(error.path(), dir.join("fifo"));
+
+    let ignored = checksum(Options::new().ignore_unknown_filetypes(true), dir);
+    assert_eq!(ignored, without);
+
+    let kinds = Arc::new(Mutex::new(Vec::new()));
+    let record = Arc::clone(&kinds);
+    let mut options = Options::new();
+    options.unknown_filetypes(move |path, _metadata, kind| {
+        record.lock().unwrap().push((path.to_owned(), kind));
+        UnknownAction::Hash
+    });
+    let hashed = checksum(&options, dir);
+    assert_ne!(hashed, without);
+    assert_eq!(
+        *kinds.lock().unwrap(),
+        [(PathBuf::from("fifo"), EntryKind::Fifo)],
+    );
+
+    // Taking precedence over ignore_unknown_filetypes.
+    options.ignore_unknown_filetypes(true);
+    assert_eq!(checksum(&options, dir), hashed);
+    options.unknown_filetypes(|_, _, _| UnknownAction::Skip);
+    options.ignore_unknown_filetypes(false);
+    assert_eq!(checksum(&options, dir), without);
+}
+
+#[test]
+fn test_encode_metadata() {
+    struct Mode;
+
+    impl EncodeMetadata for Mode {
+        fn encode(&self, _path: &Path, metadata: &Metadata, out: &mut dyn Write) -> io::Result<()> {
+            out.write_all(&metadata.mode().to_le_bytes())
+        }
+    }
+
+    struct Nothing;
+
+    impl EncodeMetadata for Nothing {
+        fn encode(
+            &self,
+            _path: &Path,
+            _metadata: &Metadata,
+            _out: &mut dyn Write,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Failing;
+
+    impl EncodeMetadata for Failing {
+        fn encode(
+            &self,
+            path: &Path,
+            _metadata: &Metadata,
+            _out: &mut dyn Write,
+        ) -> io::Result<()> {
+            if path == Path::new("file") {
+                Err(io::Error::other("no metadata"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    let fixture = Fixture::new("encode");
+    let dir = &fixture.dir;
+    let default = checksum(&Options::new(), dir);
+
+    // The mode is what is encoded by default.
+    assert_eq!(checksum(Options::new().encode_metadata(Mode), dir), default);
+    assert_ne!(
+        checksum(Options::new().encode_metadata(Nothing), dir),
+        default
+    );
+
+    let checksummer = Options::new().encode_metadata(Failing).build().unwrap();
+    let errors = checksummer.checksum(dir).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    let error = errors.iter().next().unwrap();
+    assert!(matches!(error, Error::Io { .. }));
+    assert_eq!(error.path(), dir.join("file"));
+}
+
+#[test]
+fn test_format_version() {
+    let fixture = Fixture::new("format");
+    let dir = &fixture.dir;
+
+    let latest = checksum(&Options::new(), dir);
+    assert_eq!(
+        checksum(Options::new().format_version(FORMAT_VERSION), dir),
+        latest
+    );
+    assert_eq!(
+        checksum(Options::new().format_version(FORMAT_V1), dir),
+        latest
+    );
+
+    for version in [0, FORMAT_VERSION + 1] {
+        let error = Options::new().format_version(version).build().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}